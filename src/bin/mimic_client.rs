@@ -1,7 +1,8 @@
 use std::env;
 
-use pecocloud_vm_mgr::model::{
-    Operation, VmCreateResponse, VmDeleteRequest, VmDeleteResponse, VmOperateRequest, VmOperateResponse
+use pecocloud_vm_mgr::{
+    client::VmMgrClient,
+    model::{MachineCreateConfig, VmListRequest},
 };
 use tokio::time::{sleep, Duration};
 
@@ -10,25 +11,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
     let target_addr = env::var("LISTENING_ADDR").expect("LISTENING_ADDR must be set");
     let target_port = env::var("LISTENING_PORT").expect("LISTENING_PORT must be set");
-    let target_addr = format!("{}{}", target_addr, target_port);
-    let client = reqwest::Client::new();
+    let client = VmMgrClient::new(format!("http://{}:{}", target_addr, target_port));
 
-    // User want to get the root page
-    let index_page = client
-        .get(format!("{}{}", target_addr, "/api/v1/vm"))
-        .send()
-        .await?
-        .json::<String>()
+    // User want to list the machines
+    let list_response = client
+        .list_vm(&VmListRequest {
+            status: None,
+            kernel: None,
+            limit: None,
+            cursor: None,
+        })
         .await?;
-    println!("Get index page: {}", index_page);
+    println!("Listed {} machines", list_response.vms.len());
     sleep(Duration::from_secs(3)).await;
 
     // User want to create a new machine
     let create_machine_response = client
-        .post(format!("{}{}", target_addr, "api/v1/vm"))
-        .send()
-        .await?
-        .json::<VmCreateResponse>()
+        .create_vm(
+            MachineCreateConfig {
+                memory_size_in_mib: 256,
+                vcpu_count: 1,
+                kernel_name: "vmlinux".to_string(),
+                kernel_version: "5.10".to_string(),
+                enable_hyperthreading: None,
+                initial_metadata: None,
+                volume_size_in_mib: Some(1024),
+                image_id: None,
+                root_drive_rate_limiter: None,
+                network_rx_rate_limiter: None,
+                network_tx_rate_limiter: None,
+                network_interfaces: None,
+                enable_netns: None,
+            },
+            None,
+        )
         .await?;
     let machine_vmid = create_machine_response.vmid;
     let create_at = create_machine_response.created_at;
@@ -39,97 +55,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     sleep(Duration::from_secs(3)).await;
 
     // User want to start the machine
-    let start_machine_response = client
-        .put(format!(
-            "{}{}",
-            target_addr,
-            format!("/api/v1/vm/{machine_vmid}/power_state")
-        ))
-        .json(&VmOperateRequest {
-            vmid: machine_vmid,
-            operation: Operation::Start,
-        })
-        .send()
-        .await?
-        .json::<VmOperateResponse>()
-        .await?;
-    let start_at = start_machine_response.time;
+    let start_at = client.start_vm(machine_vmid).await?.time;
     println!("Started the machine at {}", start_at);
     sleep(Duration::from_secs(3)).await;
 
     // User want to pause the machine
-    let pause_machine_response = client
-        .put(format!(
-            "{}{}",
-            target_addr,
-            format!("/api/v1/vm/{machine_vmid}/power_state")
-        ))
-        .json(&VmOperateRequest {
-            vmid: machine_vmid,
-            operation: Operation::Pause,
-        })
-        .send()
-        .await?
-        .json::<VmOperateResponse>()
-        .await?;
-    let paused_at = pause_machine_response.time;
+    let paused_at = client.pause_vm(machine_vmid).await?.time;
     println!("Paused the machine at {}", paused_at);
     sleep(Duration::from_secs(3)).await;
 
     // User want to resume the machine
-    let resume_machine_response = client
-        .put(format!(
-            "{}{}",
-            target_addr,
-            format!("/api/v1/vm/{machine_vmid}/power_state")
-        ))
-        .json(&VmOperateRequest {
-            vmid: machine_vmid,
-            operation: Operation::Resume,
-        })
-        .send()
-        .await?
-        .json::<VmOperateResponse>()
-        .await?;
-    let resumed_at = resume_machine_response.time;
+    let resumed_at = client.resume_vm(machine_vmid).await?.time;
     println!("Resumed the machine at {}", resumed_at);
     sleep(Duration::from_secs(3)).await;
 
     // User want to stop the machine
-    let stop_machine_response = client
-        .put(format!(
-            "{}{}",
-            target_addr,
-            format!("/api/v1/vm/{machine_vmid}/power_state")
-        ))
-        .json(&VmOperateRequest {
-            vmid: machine_vmid,
-            operation: Operation::Stop,
-        })
-        .send()
-        .await?
-        .json::<VmOperateResponse>()
-        .await?;
-    let stopped_at = stop_machine_response.time;
-    println!("Resumed the machine at {}", stopped_at);
+    let stopped_at = client.stop_vm(machine_vmid).await?.time;
+    println!("Stopped the machine at {}", stopped_at);
     sleep(Duration::from_secs(3)).await;
 
     // User want to discard the machine
-    let delete_machine_response =  client
-        .delete(format!(
-            "{}{}",
-            target_addr,
-            format!("/api/v1/vm/delete")
-        ))
-        .json(&VmDeleteRequest {
-            vmid: machine_vmid,
-        })
-        .send()
-        .await?
-        .json::<VmDeleteResponse>()
-        .await?;
-    let deleted_at = delete_machine_response.time;
-    println!("Resumed the machine at {}", deleted_at);
+    let deleted_at = client.delete_vm(machine_vmid).await?.time;
+    println!("Deleted the machine at {}", deleted_at);
     sleep(Duration::from_secs(3)).await;
 
     println!("User exiting...");
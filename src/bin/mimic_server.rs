@@ -2,7 +2,10 @@ use std::{default, env, sync::Mutex};
 
 use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
-use pecocloud_vm_mgr::{handler::*, pool::VmPool};
+use pecocloud_vm_mgr::{
+    handler::*,
+    pool::{restore_all_vm, VmPool},
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -10,13 +13,18 @@ async fn main() -> std::io::Result<()> {
 
     log4rs::init_file("log4rs.yaml", default::Default::default()).unwrap();
 
-    let pool = VmPool::new()
+    let mut pool = VmPool::new()
         .await
-        .map_err(|e| {
-            eprintln!("Fail to build a vm pool: {}", e.to_string());
+        .unwrap_or_else(|e| {
+            eprintln!("Fail to build a vm pool: {}", e.report());
             panic!();
         });
 
+    /* Re-adopt the vms left by a previous run of this pool */
+    if let Err(e) = restore_all_vm(&mut pool).await {
+        log::error!("Fail to restore vms of pool {}: {}", pool.pool_id, e.report());
+    }
+
     let listen_address = match env::var("LISTENING_ADDR") {
         Ok(address) => address,
         Err(_) => {
@@ -35,14 +43,33 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .service(index)
-            .service(get_root_vm_page_handler)
+            .service(list_vm_handler)
+            .service(restore_all_vm_handler)
             .service(create_vm_handler)
             .service(get_vm_status_handler)
             .service(modify_metadata_handler)
             .service(operate_vm_handler)
+            .service(update_network_rate_limit_handler)
+            .service(attach_volume_handler)
+            .service(detach_volume_handler)
+            .service(swap_volume_handler)
+            .service(update_drive_rate_limit_handler)
+            .service(create_port_forward_handler)
+            .service(attach_security_group_handler)
+            .service(detach_security_group_handler)
             .service(delete_vm_handler)
             .service(create_vm_mem_snapshot_handler)
+            .service(list_vm_mem_snapshot_handler)
+            .service(get_vm_mem_snapshot_detail_handler)
             .service(delete_vm_mem_snapshot_handler)
+            .service(restore_vm_handler)
+            .service(create_disk_snapshot_handler)
+            .service(list_disk_snapshot_handler)
+            .service(rollback_disk_snapshot_handler)
+            .service(publish_disk_snapshot_handler)
+            .service(create_security_group_handler)
+            .service(list_security_group_handler)
+            .service(delete_security_group_handler)
             .app_data(web::Data::new(Mutex::new(pool.clone())))
     })
     .bind((listen_address, port))?
@@ -0,0 +1,492 @@
+//! Typed client of the vm manager http api
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::model::*;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The manager answered with an `ErrorResponse`
+    Api(StatusCode, ErrorResponse),
+    /// The manager answered with failure but no `ErrorResponse` in the body
+    UnexpectedResponse(StatusCode, String),
+    ReqwestError(reqwest::Error),
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl ClientError {
+    /// The `ErrorResponse` code, e.g. `VM_NOT_FOUND`, if the manager sent one
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Api(_, e) => Some(&e.code),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Api(status, _) | ClientError::UnexpectedResponse(status, _) => {
+                Some(*status)
+            }
+            ClientError::ReqwestError(e) => e.status(),
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Api(status, e) => write!(
+                f,
+                "{} {}: {} (request {})",
+                status, e.code, e.message, e.request_id
+            ),
+            ClientError::UnexpectedResponse(status, body) => write!(f, "{}: {}", status, body),
+            ClientError::ReqwestError(_) => write!(f, "Reqwest client error"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::ReqwestError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::ReqwestError(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VmMgrClient {
+    /// e.g. `http://localhost:8080`
+    addr: String,
+    client: reqwest::Client,
+}
+
+impl VmMgrClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self::with_client(addr, reqwest::Client::new())
+    }
+
+    pub fn with_client(addr: impl Into<String>, client: reqwest::Client) -> Self {
+        let addr: String = addr.into();
+        Self {
+            addr: addr.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.addr, path)
+    }
+
+    async fn parse<T: DeserializeOwned>(res: Response) -> ClientResult<T> {
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res.json::<T>().await?);
+        }
+        let body = res.text().await?;
+        match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(e) => Err(ClientError::Api(status, e)),
+            Err(_) => Err(ClientError::UnexpectedResponse(status, body)),
+        }
+    }
+
+    pub async fn list_vm(&self, request: &VmListRequest) -> ClientResult<VmListResponse> {
+        let res = self
+            .client
+            .get(self.url("/api/v1/vm"))
+            .query(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn restore_all_vm(&self) -> ClientResult<VmRestoreAllResponse> {
+        let res = self
+            .client
+            .post(self.url("/api/v1/vm/restore_all"))
+            .json(&VmRestoreAllRequest {})
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn create_vm(
+        &self,
+        config: MachineCreateConfig,
+        from_snapshot: Option<VmSnapshotSource>,
+    ) -> ClientResult<VmCreateResponse> {
+        let res = self
+            .client
+            .post(self.url("/api/v1/vm"))
+            .json(&VmCreateRequest {
+                config,
+                from_snapshot,
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn get_vm_status(&self, vmid: Uuid) -> ClientResult<VmViewInfo> {
+        let res = self
+            .client
+            .get(self.url(&format!("/api/v1/vm/{vmid}")))
+            .json(&VmQueryStatusRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn modify_metadata(
+        &self,
+        vmid: Uuid,
+        metadata: impl Into<String>,
+    ) -> ClientResult<VmModifyMetadataResponse> {
+        let res = self
+            .client
+            .put(self.url(&format!("/api/v1/vm/{vmid}")))
+            .json(&VmModifyMetadataRequest {
+                vmid,
+                metadata: metadata.into(),
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn operate_vm(
+        &self,
+        vmid: Uuid,
+        operation: Operation,
+    ) -> ClientResult<VmOperateResponse> {
+        let res = self
+            .client
+            .put(self.url(&format!("/api/v1/vm/{vmid}/power_state")))
+            .json(&VmOperateRequest { vmid, operation })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn start_vm(&self, vmid: Uuid) -> ClientResult<VmOperateResponse> {
+        self.operate_vm(vmid, Operation::Start).await
+    }
+
+    pub async fn pause_vm(&self, vmid: Uuid) -> ClientResult<VmOperateResponse> {
+        self.operate_vm(vmid, Operation::Pause).await
+    }
+
+    pub async fn resume_vm(&self, vmid: Uuid) -> ClientResult<VmOperateResponse> {
+        self.operate_vm(vmid, Operation::Resume).await
+    }
+
+    pub async fn stop_vm(&self, vmid: Uuid) -> ClientResult<VmOperateResponse> {
+        self.operate_vm(vmid, Operation::Stop).await
+    }
+
+    pub async fn update_network_rate_limit(
+        &self,
+        request: &VmNetworkRateLimitRequest,
+    ) -> ClientResult<VmNetworkRateLimitResponse> {
+        let res = self
+            .client
+            .patch(self.url(&format!(
+                "/api/v1/vm/{}/network/{}",
+                request.vmid, request.iface_id
+            )))
+            .json(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn attach_volume(
+        &self,
+        request: &VmVolumeAttachRequest,
+    ) -> ClientResult<VmVolumeAttachResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!("/api/v1/vm/{}/volumes", request.vmid)))
+            .json(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn detach_volume(
+        &self,
+        request: &VmVolumeDetachRequest,
+    ) -> ClientResult<VmVolumeDetachResponse> {
+        let res = self
+            .client
+            .delete(self.url(&format!("/api/v1/vm/{}/volumes", request.vmid)))
+            .json(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn swap_volume(
+        &self,
+        request: &VmVolumeSwapRequest,
+    ) -> ClientResult<VmVolumeSwapResponse> {
+        let res = self
+            .client
+            .patch(self.url(&format!(
+                "/api/v1/vm/{}/volumes/{}",
+                request.vmid, request.drive_id
+            )))
+            .json(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn update_drive_rate_limit(
+        &self,
+        request: &VmDriveRateLimitRequest,
+    ) -> ClientResult<VmDriveRateLimitResponse> {
+        let res = self
+            .client
+            .patch(self.url(&format!(
+                "/api/v1/vm/{}/volumes/{}/rate_limit",
+                request.vmid, request.drive_id
+            )))
+            .json(request)
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn create_port_forward(
+        &self,
+        vmid: Uuid,
+        protocol: ForwardProtocol,
+        guest_port: u16,
+    ) -> ClientResult<VmPortForwardResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!("/api/v1/vm/{vmid}/port_forward")))
+            .json(&VmPortForwardRequest {
+                vmid,
+                protocol,
+                guest_port,
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn delete_vm(&self, vmid: Uuid) -> ClientResult<VmDeleteResponse> {
+        let res = self
+            .client
+            .delete(self.url("/api/v1/vm/delete"))
+            .json(&VmDeleteRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn create_vm_mem_snapshot(&self, vmid: Uuid) -> ClientResult<VmCreateVMMSResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!("/api/v1/vm/{vmid}/vm_mem_snapshot")))
+            .json(&VmCreateVMMSRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn list_vm_mem_snapshot(&self, vmid: Uuid) -> ClientResult<VmSnapshotListResponse> {
+        let res = self
+            .client
+            .get(self.url(&format!("/api/v1/vm/{vmid}/vm_mem_snapshot")))
+            .json(&VmSnapshotListRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn get_vm_mem_snapshot_detail(
+        &self,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+    ) -> ClientResult<SnapshotInfo> {
+        let res = self
+            .client
+            .get(self.url(&format!("/api/v1/vm/{vmid}/vm_mem_snapshot/{snapshot_id}")))
+            .json(&VmSnapshotDetailRequest { vmid, snapshot_id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn delete_vm_mem_snapshot(
+        &self,
+        vmid: Uuid,
+        vm_mem_snapshot_id: Uuid,
+    ) -> ClientResult<VmDeleteVMMSResponse> {
+        let res = self
+            .client
+            .delete(self.url(&format!(
+                "/api/v1/vm/{vmid}/vm_mem_snapshot/{vm_mem_snapshot_id}"
+            )))
+            .json(&VmDeleteVMMSRequest {
+                vmid,
+                vm_mem_snapshot_id,
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn restore_vm(
+        &self,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+        resume: bool,
+    ) -> ClientResult<VmLoadSnapshotResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!(
+                "/api/v1/vm/{vmid}/vm_mem_snapshot/{snapshot_id}/restore"
+            )))
+            .json(&VmLoadSnapshotRequest {
+                vmid,
+                snapshot_id,
+                resume,
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn create_disk_snapshot(&self, vmid: Uuid) -> ClientResult<DiskSnapshotInfo> {
+        let res = self
+            .client
+            .post(self.url(&format!("/api/v1/vm/{vmid}/disk_snapshot")))
+            .json(&VmCreateDiskSnapshotRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn list_disk_snapshot(&self, vmid: Uuid) -> ClientResult<VmDiskSnapshotListResponse> {
+        let res = self
+            .client
+            .get(self.url(&format!("/api/v1/vm/{vmid}/disk_snapshot")))
+            .json(&VmDiskSnapshotListRequest { vmid })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn rollback_disk_snapshot(
+        &self,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+    ) -> ClientResult<VmDiskSnapshotRollbackResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!(
+                "/api/v1/vm/{vmid}/disk_snapshot/{snapshot_id}/rollback"
+            )))
+            .json(&VmDiskSnapshotRollbackRequest { vmid, snapshot_id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn publish_disk_snapshot(
+        &self,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+    ) -> ClientResult<VmPublishImageResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!(
+                "/api/v1/vm/{vmid}/disk_snapshot/{snapshot_id}/publish"
+            )))
+            .json(&VmPublishImageRequest { vmid, snapshot_id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn create_security_group(
+        &self,
+        name: impl Into<String>,
+        rules: Vec<SecurityGroupRule>,
+    ) -> ClientResult<SecurityGroupCreateResponse> {
+        let res = self
+            .client
+            .post(self.url("/api/v1/security_group"))
+            .json(&SecurityGroupCreateRequest {
+                name: name.into(),
+                rules,
+            })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn list_security_group(&self) -> ClientResult<SecurityGroupListResponse> {
+        let res = self
+            .client
+            .get(self.url("/api/v1/security_group"))
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn delete_security_group(
+        &self,
+        id: Uuid,
+    ) -> ClientResult<SecurityGroupDeleteResponse> {
+        let res = self
+            .client
+            .delete(self.url("/api/v1/security_group"))
+            .json(&SecurityGroupDeleteRequest { id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn attach_security_group(
+        &self,
+        vmid: Uuid,
+        group_id: Uuid,
+    ) -> ClientResult<VmSecurityGroupResponse> {
+        let res = self
+            .client
+            .post(self.url(&format!("/api/v1/vm/{vmid}/security_group")))
+            .json(&VmSecurityGroupRequest { vmid, group_id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+
+    pub async fn detach_security_group(
+        &self,
+        vmid: Uuid,
+        group_id: Uuid,
+    ) -> ClientResult<VmSecurityGroupResponse> {
+        let res = self
+            .client
+            .delete(self.url(&format!("/api/v1/vm/{vmid}/security_group")))
+            .json(&VmSecurityGroupRequest { vmid, group_id })
+            .send()
+            .await?;
+        Self::parse(res).await
+    }
+}
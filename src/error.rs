@@ -1,34 +1,59 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use rustcracker::components::machine::MachineError;
 use uuid::Uuid;
 
+use crate::model::{machine_state_name, ErrorResponse, MachineState};
+
+#[derive(Debug)]
 pub enum VmManageError {
     VmNotFound(Uuid),
+    VmMemSnapshotNotFound(Uuid),
+    DiskSnapshotNotFound(Uuid),
+    /// (vmid, iface_id)
+    NetworkInterfaceNotFound(Uuid, String),
+    SecurityGroupNotFound(Uuid),
+    /// (vmid, drive_id)
+    DriveNotFound(Uuid, String),
+    /// (volume_id, vmid it is attached to)
+    VolumeInUse(Uuid, Uuid),
+    /// Name of a tap that exists on the host without being ours
+    TapInUse(String),
+    /// (group_id, attached vms)
+    SecurityGroupInUse(Uuid, Vec<Uuid>),
     KernelNotFound(String),
+    InvalidArgument(String),
+    /// (vmid, operation, current state)
+    InvalidTransition(Uuid, String, MachineState),
+
+    SerdeError(serde_json::Error),
+    EtcdError(Box<etcd_client::Error>),
+    ReqwestError(reqwest::Error),
+    IoError(std::io::Error),
+    /// (service, status, message) of an upstream service answering with failure,
+    /// the message taken from its `ErrorResponse` if it sent one
+    UpstreamStatus(String, reqwest::StatusCode, Option<String>),
+
+    DBConnection(sqlx::Error),
+    DBTransaction(sqlx::Error),
+    DBCreateTable(sqlx::Error),
+    DBInsertion(sqlx::Error),
+    DBDeleting(sqlx::Error),
+    DBFetching(sqlx::Error),
+    DBUpdating(sqlx::Error),
 
-    SerdeError,
-    EtcdError,
-    ReqwestError,
-    IoError,
-
-    DBConnection,
-    DBDropTable,
-    DBCreateTable,
-    DBInsertion,
-    DBDeleting,
-    DBFetching,
-    DBUpdating,
-
-    MachineCreate,
-    MachineDumpCore,
-    MachineRebuild,
-    MachineStart,
-    MachinePause,
-    MachineResume,
-    MachineStop,
-    MachineDelete,
-    MachineQuery,
-
-    VmMemSnapshotCreate,
-    VmMemSnapshotDelete,
+    MachineCreate(MachineError),
+    MachineDumpCore(MachineError),
+    MachineRebuild(MachineError),
+    MachineStart(MachineError),
+    MachinePause(MachineError),
+    MachineResume(MachineError),
+    MachineStop(MachineError),
+    MachineQuery(MachineError),
+    MachineMetadata(MachineError),
+    MachineUpdate(MachineError),
+
+    VmMemSnapshotCreate(MachineError),
+    VmMemSnapshotLoad(MachineError),
 
     EnvSocket,
     EnvLogDir,
@@ -37,72 +62,255 @@ pub enum VmManageError {
     EnvAgentRequest,
     EnvKernelList,
     EnvMemoryDir,
+    EnvPoolId,
+    EnvGuestNetwork,
+    EnvPortForwardRange,
+    EnvNetnsTransit,
 
-    NetworkError,
+    NetworkError(Box<dyn std::error::Error + Send + Sync>),
 }
 
 pub type VmManageResult<T> = Result<T, VmManageError>;
 
 impl std::fmt::Display for VmManageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
-            VmManageError::VmNotFound(vmid) => format!("Vm {vmid} not found"),
-            VmManageError::KernelNotFound(s) => format!("Kernel {s} not found"),
-            VmManageError::EtcdError => format!("ETCD error"),
-            VmManageError::ReqwestError => format!("Reqwest client error"),
-            VmManageError::SerdeError => format!("Serde error"),
-            VmManageError::IoError => format!("Io error"),
-            VmManageError::DBConnection => format!("Connect database error"),
-            VmManageError::DBCreateTable => format!("Create table error"),
-            VmManageError::DBDropTable => format!("Drop table error"),
-            VmManageError::DBInsertion => format!("Insert element error"),
-            VmManageError::DBDeleting => format!("Delete element error"),
-            VmManageError::DBFetching => format!("Fetch element error"),
-            VmManageError::DBUpdating => format!("Updating element error"),
-            VmManageError::MachineCreate => format!("Create machine error"),
-            VmManageError::MachineDumpCore => format!("Dump machine error"),
-            VmManageError::MachineRebuild => format!("Rebuild machine error"),
-            VmManageError::MachineStart => format!("Start machine error"),
-            VmManageError::MachinePause => format!("Pause machine error"),
-            VmManageError::MachineResume => format!("Resume machine error"),
-            VmManageError::MachineStop => format!("Stop machine error"),
-            VmManageError::MachineDelete => format!("Delete machine error"),
-            VmManageError::MachineQuery => format!("Query machine error"),
-            VmManageError::VmMemSnapshotCreate => format!("Create vm/mem snapshot error"),
-            VmManageError::VmMemSnapshotDelete => format!("Delete vm/mem snapshot error"),
-            VmManageError::EnvSocket => format!("SOCKET_DIR must be set"),
-            VmManageError::EnvLogDir => format!("LOGS_DIR must be set"),
-            VmManageError::EnvMetricsDir => format!("METRICS_DIR must be set"),
-            VmManageError::EnvAgentInit => format!("AGENT_INIT_TIMEOUT must be set"),
-            VmManageError::EnvAgentRequest => format!("AGENT_AGENT_REQUEST_TIMEOUT_TIMEOUT must be set"),
-            VmManageError::EnvKernelList => format!("KERNEL_LIST_FILE must be set"),
-            VmManageError::EnvMemoryDir => format!("MEMORY_SNAPSHOT_DIR must be set"),
-            VmManageError::NetworkError => format!("Network error"),
-        };
-        write!(f, "{}", s)
+        match self {
+            VmManageError::VmNotFound(vmid) => write!(f, "Vm {vmid} not found"),
+            VmManageError::VmMemSnapshotNotFound(id) => write!(f, "Vm/mem snapshot {id} not found"),
+            VmManageError::DiskSnapshotNotFound(id) => write!(f, "Disk snapshot {id} not found"),
+            VmManageError::NetworkInterfaceNotFound(vmid, iface_id) => {
+                write!(f, "Network interface {iface_id} of vm {vmid} not found")
+            }
+            VmManageError::SecurityGroupNotFound(id) => write!(f, "Security group {id} not found"),
+            VmManageError::DriveNotFound(vmid, drive_id) => {
+                write!(f, "Drive {drive_id} of vm {vmid} not found")
+            }
+            VmManageError::VolumeInUse(volume_id, vmid) => {
+                write!(f, "Volume {volume_id} is attached to vm {vmid}")
+            }
+            VmManageError::TapInUse(name) => write!(f, "Tap {name} already exists on the host"),
+            VmManageError::SecurityGroupInUse(id, vmids) => write!(
+                f,
+                "Security group {id} is attached to {} vm(s): {:?}",
+                vmids.len(),
+                vmids
+            ),
+            VmManageError::KernelNotFound(s) => write!(f, "Kernel {s} not found"),
+            VmManageError::InvalidArgument(s) => write!(f, "Invalid argument: {s}"),
+            VmManageError::InvalidTransition(vmid, operation, state) => write!(
+                f,
+                "Cannot {operation} vm {vmid} in state {}",
+                machine_state_name(*state)
+            ),
+            VmManageError::EtcdError(_) => write!(f, "ETCD error"),
+            VmManageError::ReqwestError(_) => write!(f, "Reqwest client error"),
+            VmManageError::SerdeError(_) => write!(f, "Serde error"),
+            VmManageError::IoError(_) => write!(f, "Io error"),
+            VmManageError::UpstreamStatus(service, status, None) => {
+                write!(f, "{service} responded with {status}")
+            }
+            VmManageError::UpstreamStatus(service, status, Some(message)) => {
+                write!(f, "{service} responded with {status}: {message}")
+            }
+            VmManageError::DBConnection(_) => write!(f, "Connect database error"),
+            VmManageError::DBTransaction(_) => write!(f, "Database transaction error"),
+            VmManageError::DBCreateTable(_) => write!(f, "Create table error"),
+            VmManageError::DBInsertion(_) => write!(f, "Insert element error"),
+            VmManageError::DBDeleting(_) => write!(f, "Delete element error"),
+            VmManageError::DBFetching(_) => write!(f, "Fetch element error"),
+            VmManageError::DBUpdating(_) => write!(f, "Updating element error"),
+            VmManageError::MachineCreate(_) => write!(f, "Create machine error"),
+            VmManageError::MachineDumpCore(_) => write!(f, "Dump machine error"),
+            VmManageError::MachineRebuild(_) => write!(f, "Rebuild machine error"),
+            VmManageError::MachineStart(_) => write!(f, "Start machine error"),
+            VmManageError::MachinePause(_) => write!(f, "Pause machine error"),
+            VmManageError::MachineResume(_) => write!(f, "Resume machine error"),
+            VmManageError::MachineStop(_) => write!(f, "Stop machine error"),
+            VmManageError::MachineQuery(_) => write!(f, "Query machine error"),
+            VmManageError::MachineMetadata(_) => write!(f, "Update machine metadata error"),
+            VmManageError::MachineUpdate(_) => write!(f, "Update machine device error"),
+            VmManageError::VmMemSnapshotCreate(_) => write!(f, "Create vm/mem snapshot error"),
+            VmManageError::VmMemSnapshotLoad(_) => write!(f, "Load vm/mem snapshot error"),
+            VmManageError::EnvSocket => write!(f, "SOCKET_DIR must be set"),
+            VmManageError::EnvLogDir => write!(f, "LOGS_DIR must be set"),
+            VmManageError::EnvMetricsDir => write!(f, "METRICS_DIR must be set"),
+            VmManageError::EnvAgentInit => write!(f, "AGENT_INIT_TIMEOUT must be set"),
+            VmManageError::EnvAgentRequest => {
+                write!(f, "AGENT_AGENT_REQUEST_TIMEOUT_TIMEOUT must be set")
+            }
+            VmManageError::EnvKernelList => write!(f, "KERNEL_LIST_FILE must be set"),
+            VmManageError::EnvMemoryDir => write!(f, "MEMORY_SNAPSHOT_DIR must be set"),
+            VmManageError::EnvPoolId => write!(f, "POOL_ID and POOL_ID_FILE must hold a valid uuid"),
+            VmManageError::EnvGuestNetwork => {
+                write!(f, "GUEST_NETWORK_CIDR must be a valid ipv4 cidr")
+            }
+            VmManageError::EnvPortForwardRange => {
+                write!(f, "PORT_FORWARD_RANGE must be a port range like 20000-29999")
+            }
+            VmManageError::EnvNetnsTransit => {
+                write!(f, "NETNS_TRANSIT_CIDR must be a valid ipv4 cidr")
+            }
+            VmManageError::NetworkError(_) => write!(f, "Network error"),
+        }
+    }
+}
+
+impl std::error::Error for VmManageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmManageError::SerdeError(e) => Some(e),
+            VmManageError::EtcdError(e) => Some(e.as_ref()),
+            VmManageError::ReqwestError(e) => Some(e),
+            VmManageError::IoError(e) => Some(e),
+            VmManageError::DBConnection(e)
+            | VmManageError::DBTransaction(e)
+            | VmManageError::DBCreateTable(e)
+            | VmManageError::DBInsertion(e)
+            | VmManageError::DBDeleting(e)
+            | VmManageError::DBFetching(e)
+            | VmManageError::DBUpdating(e) => Some(e),
+            VmManageError::MachineCreate(e)
+            | VmManageError::MachineDumpCore(e)
+            | VmManageError::MachineRebuild(e)
+            | VmManageError::MachineStart(e)
+            | VmManageError::MachinePause(e)
+            | VmManageError::MachineResume(e)
+            | VmManageError::MachineStop(e)
+            | VmManageError::MachineQuery(e)
+            | VmManageError::MachineMetadata(e)
+            | VmManageError::MachineUpdate(e)
+            | VmManageError::VmMemSnapshotCreate(e)
+            | VmManageError::VmMemSnapshotLoad(e) => Some(e),
+            VmManageError::NetworkError(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl VmManageError {
+    /// The error followed by the whole chain of its causes
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            report.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+        report
+    }
+
+    /// Stable machine-readable code of the error, part of the API
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmManageError::VmNotFound(..) => "VM_NOT_FOUND",
+            VmManageError::VmMemSnapshotNotFound(..) => "VM_MEM_SNAPSHOT_NOT_FOUND",
+            VmManageError::DiskSnapshotNotFound(..) => "DISK_SNAPSHOT_NOT_FOUND",
+            VmManageError::NetworkInterfaceNotFound(..) => "NETWORK_INTERFACE_NOT_FOUND",
+            VmManageError::SecurityGroupNotFound(..) => "SECURITY_GROUP_NOT_FOUND",
+            VmManageError::DriveNotFound(..) => "DRIVE_NOT_FOUND",
+            VmManageError::VolumeInUse(..) => "VOLUME_IN_USE",
+            VmManageError::TapInUse(..) => "TAP_IN_USE",
+            VmManageError::SecurityGroupInUse(..) => "SECURITY_GROUP_IN_USE",
+            VmManageError::KernelNotFound(..) => "KERNEL_NOT_FOUND",
+            VmManageError::InvalidArgument(..) => "INVALID_ARGUMENT",
+            VmManageError::InvalidTransition(..) => "INVALID_TRANSITION",
+            VmManageError::SerdeError(..) => "SERDE_ERROR",
+            VmManageError::EtcdError(..) => "ETCD_ERROR",
+            VmManageError::ReqwestError(..) => "REQWEST_ERROR",
+            VmManageError::IoError(..) => "IO_ERROR",
+            VmManageError::UpstreamStatus(..) => "UPSTREAM_STATUS",
+            VmManageError::DBConnection(..) => "DB_CONNECTION",
+            VmManageError::DBTransaction(..) => "DB_TRANSACTION",
+            VmManageError::DBCreateTable(..) => "DB_CREATE_TABLE",
+            VmManageError::DBInsertion(..) => "DB_INSERTION",
+            VmManageError::DBDeleting(..) => "DB_DELETING",
+            VmManageError::DBFetching(..) => "DB_FETCHING",
+            VmManageError::DBUpdating(..) => "DB_UPDATING",
+            VmManageError::MachineCreate(..) => "MACHINE_CREATE",
+            VmManageError::MachineDumpCore(..) => "MACHINE_DUMP_CORE",
+            VmManageError::MachineRebuild(..) => "MACHINE_REBUILD",
+            VmManageError::MachineStart(..) => "MACHINE_START",
+            VmManageError::MachinePause(..) => "MACHINE_PAUSE",
+            VmManageError::MachineResume(..) => "MACHINE_RESUME",
+            VmManageError::MachineStop(..) => "MACHINE_STOP",
+            VmManageError::MachineQuery(..) => "MACHINE_QUERY",
+            VmManageError::MachineMetadata(..) => "MACHINE_METADATA",
+            VmManageError::MachineUpdate(..) => "MACHINE_UPDATE",
+            VmManageError::VmMemSnapshotCreate(..) => "VM_MEM_SNAPSHOT_CREATE",
+            VmManageError::VmMemSnapshotLoad(..) => "VM_MEM_SNAPSHOT_LOAD",
+            VmManageError::EnvSocket => "ENV_SOCKET",
+            VmManageError::EnvLogDir => "ENV_LOG_DIR",
+            VmManageError::EnvMetricsDir => "ENV_METRICS_DIR",
+            VmManageError::EnvAgentInit => "ENV_AGENT_INIT",
+            VmManageError::EnvAgentRequest => "ENV_AGENT_REQUEST",
+            VmManageError::EnvKernelList => "ENV_KERNEL_LIST",
+            VmManageError::EnvMemoryDir => "ENV_MEMORY_DIR",
+            VmManageError::EnvPoolId => "ENV_POOL_ID",
+            VmManageError::EnvGuestNetwork => "ENV_GUEST_NETWORK",
+            VmManageError::EnvPortForwardRange => "ENV_PORT_FORWARD_RANGE",
+            VmManageError::EnvNetnsTransit => "ENV_NETNS_TRANSIT",
+            VmManageError::NetworkError(..) => "NETWORK_ERROR",
+        }
+    }
+}
+
+impl ResponseError for VmManageError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            VmManageError::VmNotFound(_)
+            | VmManageError::VmMemSnapshotNotFound(_)
+            | VmManageError::DiskSnapshotNotFound(_)
+            | VmManageError::NetworkInterfaceNotFound(..)
+            | VmManageError::SecurityGroupNotFound(_)
+            | VmManageError::DriveNotFound(..)
+            | VmManageError::KernelNotFound(_) => StatusCode::NOT_FOUND,
+            VmManageError::InvalidTransition(..)
+            | VmManageError::SecurityGroupInUse(..)
+            | VmManageError::VolumeInUse(..)
+            | VmManageError::TapInUse(_) => StatusCode::CONFLICT,
+            VmManageError::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+            VmManageError::ReqwestError(_)
+            | VmManageError::UpstreamStatus(..)
+            | VmManageError::NetworkError(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let request_id = Uuid::new_v4();
+        match status.is_server_error() {
+            true => log::error!("Request {} failed: {}", request_id, self.report()),
+            false => log::warn!("Request {} failed: {}", request_id, self.report()),
+        }
+        HttpResponse::build(status).json(ErrorResponse {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            request_id,
+        })
     }
 }
 
 impl From<serde_json::Error> for VmManageError {
-    fn from(_e: serde_json::Error) -> Self {
-        VmManageError::SerdeError
+    fn from(e: serde_json::Error) -> Self {
+        VmManageError::SerdeError(e)
     }
 }
 
 impl From<etcd_client::Error> for VmManageError {
-    fn from(_e: etcd_client::Error) -> Self {
-        VmManageError::EtcdError
+    fn from(e: etcd_client::Error) -> Self {
+        VmManageError::EtcdError(Box::new(e))
     }
 }
 
 impl From<reqwest::Error> for VmManageError {
-    fn from(_e: reqwest::Error) -> Self {
-        VmManageError::ReqwestError
+    fn from(e: reqwest::Error) -> Self {
+        VmManageError::ReqwestError(e)
     }
 }
 
 impl From<std::io::Error> for VmManageError {
-    fn from(_e: std::io::Error) -> Self {
-        VmManageError::IoError
+    fn from(e: std::io::Error) -> Self {
+        VmManageError::IoError(e)
     }
-}
\ No newline at end of file
+}
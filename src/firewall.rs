@@ -0,0 +1,304 @@
+//! Security groups rendered into one nftables table per tap
+use std::process::Stdio;
+
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{
+    error::{VmManageError, VmManageResult},
+    model::{RuleDirection, RuleProtocol, SecurityGroupRule},
+    network_mgr::Ipv4Cidr,
+};
+
+/// Check a rule before it gets stored
+pub fn validate_rule(rule: &SecurityGroupRule) -> VmManageResult<()> {
+    rule.cidr.parse::<Ipv4Cidr>()?;
+    match (rule.protocol, rule.port_from, rule.port_to) {
+        (RuleProtocol::Tcp | RuleProtocol::Udp, Some(from), Some(to)) if from > to => Err(
+            VmManageError::InvalidArgument(format!("empty port range {}-{}", from, to)),
+        ),
+        (RuleProtocol::Tcp | RuleProtocol::Udp, _, _) => Ok(()),
+        (_, None, None) => Ok(()),
+        (protocol, _, _) => Err(VmManageError::InvalidArgument(format!(
+            "{:?} rules take no ports",
+            protocol
+        ))),
+    }
+}
+
+fn table_name(host_dev_name: &str) -> String {
+    format!("pecocloud_{}", host_dev_name)
+}
+
+fn render_rule(host_dev_name: &str, rule: &SecurityGroupRule) -> String {
+    /* Ingress: toward the guest, matched on the source; egress the other way round */
+    let (iface, addr) = match rule.direction {
+        RuleDirection::Ingress => ("oifname", "saddr"),
+        RuleDirection::Egress => ("iifname", "daddr"),
+    };
+    let mut line = format!("{} \"{}\" ip {} {}", iface, host_dev_name, addr, rule.cidr);
+    let proto = match rule.protocol {
+        RuleProtocol::Tcp => Some("tcp"),
+        RuleProtocol::Udp => Some("udp"),
+        RuleProtocol::Icmp => {
+            line.push_str(" ip protocol icmp");
+            None
+        }
+        RuleProtocol::Any => None,
+    };
+    if let Some(proto) = proto {
+        match (rule.port_from, rule.port_to) {
+            (Some(from), Some(to)) => line.push_str(&format!(" {} dport {}-{}", proto, from, to)),
+            (Some(port), None) | (None, Some(port)) => {
+                line.push_str(&format!(" {} dport {}", proto, port))
+            }
+            (None, None) => line.push_str(&format!(" meta l4proto {}", proto)),
+        }
+    }
+    line.push_str(" accept");
+    line
+}
+
+/// The table of a tap: replies and port forwards always pass, new ingress needs a rule,
+/// egress is open until some egress rule narrows it
+fn render(host_dev_name: &str, rules: &[SecurityGroupRule]) -> String {
+    let table = table_name(host_dev_name);
+    let mut chain = vec![
+        "type filter hook forward priority 0; policy accept;".to_string(),
+        format!(
+            "iifname \"{}\" ct state established,related accept",
+            host_dev_name
+        ),
+        format!(
+            "oifname \"{}\" ct state established,related accept",
+            host_dev_name
+        ),
+        /* Port forwards were asked for explicitly */
+        format!("oifname \"{}\" ct status dnat accept", host_dev_name),
+    ];
+    chain.extend(rules.iter().map(|rule| render_rule(host_dev_name, rule)));
+    if rules
+        .iter()
+        .any(|rule| rule.direction == RuleDirection::Egress)
+    {
+        chain.push(format!("iifname \"{}\" drop", host_dev_name));
+    }
+    chain.push(format!("oifname \"{}\" drop", host_dev_name));
+
+    /* Declare then delete so the new table replaces the old one atomically */
+    format!(
+        "table inet {table} {{}}\ndelete table inet {table}\ntable inet {table} {{\n    chain forward {{\n        {}\n    }}\n}}\n",
+        chain.join("\n        ")
+    )
+}
+
+/// Run an nft script, in the network namespace `netns` if given
+async fn nft(netns: Option<&str>, script: &str) -> VmManageResult<()> {
+    let mut command = match netns {
+        Some(netns) => {
+            let mut command = Command::new("ip");
+            command.args(["netns", "exec", netns, "nft"]);
+            command
+        }
+        None => Command::new("nft"),
+    };
+    let mut child = command
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| VmManageError::NetworkError(Box::new(e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .await
+            .map_err(|e| VmManageError::NetworkError(Box::new(e)))?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| VmManageError::NetworkError(Box::new(e)))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(VmManageError::NetworkError(
+            format!(
+                "nft failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+        )),
+    }
+}
+
+/// (Re)write the firewall of a tap from the rules of every group of its vm.
+/// Taps inside a namespace are filtered there, where port forwards are not
+/// seen as such and need an ingress rule.
+pub async fn apply(
+    netns: Option<&str>,
+    host_dev_name: &str,
+    rules: &[SecurityGroupRule],
+) -> VmManageResult<()> {
+    log::trace!(
+        "Applying {} firewall rules to {}",
+        rules.len(),
+        host_dev_name
+    );
+    nft(netns, &render(host_dev_name, rules)).await
+}
+
+/// Drop the firewall of a tap, succeeds if there is none
+pub async fn remove(netns: Option<&str>, host_dev_name: &str) -> VmManageResult<()> {
+    log::trace!("Removing firewall of {}", host_dev_name);
+    let table = table_name(host_dev_name);
+    nft(
+        netns,
+        &format!("table inet {table} {{}}\ndelete table inet {table}\n"),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        direction: RuleDirection,
+        protocol: RuleProtocol,
+        port_from: Option<u16>,
+        port_to: Option<u16>,
+    ) -> SecurityGroupRule {
+        SecurityGroupRule {
+            direction,
+            protocol,
+            port_from,
+            port_to,
+            cidr: "10.0.0.0/8".to_string(),
+        }
+    }
+
+    #[test]
+    fn validate_rule_accepts_ports_of_tcp_and_udp_only() {
+        for protocol in [RuleProtocol::Tcp, RuleProtocol::Udp] {
+            for (from, to) in [
+                (None, None),
+                (Some(22), None),
+                (None, Some(22)),
+                (Some(22), Some(22)),
+                (Some(8000), Some(8080)),
+            ] {
+                assert!(validate_rule(&rule(RuleDirection::Ingress, protocol, from, to)).is_ok());
+            }
+            assert!(validate_rule(&rule(
+                RuleDirection::Ingress,
+                protocol,
+                Some(8080),
+                Some(8000)
+            ))
+            .is_err());
+        }
+        for protocol in [RuleProtocol::Icmp, RuleProtocol::Any] {
+            assert!(validate_rule(&rule(RuleDirection::Egress, protocol, None, None)).is_ok());
+            for (from, to) in [(Some(22), None), (None, Some(22)), (Some(22), Some(22))] {
+                assert!(validate_rule(&rule(RuleDirection::Egress, protocol, from, to)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn validate_rule_rejects_bad_cidr() {
+        let mut bad = rule(RuleDirection::Ingress, RuleProtocol::Any, None, None);
+        bad.cidr = "10.0.0.0".to_string();
+        assert!(validate_rule(&bad).is_err());
+        bad.cidr = "10.0.0.0/33".to_string();
+        assert!(validate_rule(&bad).is_err());
+    }
+
+    #[test]
+    fn render_rule_golden() {
+        let cases = [
+            (
+                rule(RuleDirection::Ingress, RuleProtocol::Tcp, Some(22), None),
+                "oifname \"tap0\" ip saddr 10.0.0.0/8 tcp dport 22 accept",
+            ),
+            (
+                rule(RuleDirection::Ingress, RuleProtocol::Tcp, None, Some(443)),
+                "oifname \"tap0\" ip saddr 10.0.0.0/8 tcp dport 443 accept",
+            ),
+            (
+                rule(
+                    RuleDirection::Ingress,
+                    RuleProtocol::Udp,
+                    Some(8000),
+                    Some(8080),
+                ),
+                "oifname \"tap0\" ip saddr 10.0.0.0/8 udp dport 8000-8080 accept",
+            ),
+            (
+                rule(RuleDirection::Egress, RuleProtocol::Udp, None, None),
+                "iifname \"tap0\" ip daddr 10.0.0.0/8 meta l4proto udp accept",
+            ),
+            (
+                rule(RuleDirection::Egress, RuleProtocol::Tcp, None, None),
+                "iifname \"tap0\" ip daddr 10.0.0.0/8 meta l4proto tcp accept",
+            ),
+            (
+                rule(RuleDirection::Ingress, RuleProtocol::Icmp, None, None),
+                "oifname \"tap0\" ip saddr 10.0.0.0/8 ip protocol icmp accept",
+            ),
+            (
+                rule(RuleDirection::Egress, RuleProtocol::Any, None, None),
+                "iifname \"tap0\" ip daddr 10.0.0.0/8 accept",
+            ),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(render_rule("tap0", &rule), expected);
+        }
+    }
+
+    #[test]
+    fn render_golden_without_egress_rules() {
+        let rules = [rule(
+            RuleDirection::Ingress,
+            RuleProtocol::Tcp,
+            Some(22),
+            None,
+        )];
+        assert_eq!(
+            render("tap0", &rules),
+            r#"table inet pecocloud_tap0 {}
+delete table inet pecocloud_tap0
+table inet pecocloud_tap0 {
+    chain forward {
+        type filter hook forward priority 0; policy accept;
+        iifname "tap0" ct state established,related accept
+        oifname "tap0" ct state established,related accept
+        oifname "tap0" ct status dnat accept
+        oifname "tap0" ip saddr 10.0.0.0/8 tcp dport 22 accept
+        oifname "tap0" drop
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn render_golden_with_egress_rules() {
+        let rules = [rule(RuleDirection::Egress, RuleProtocol::Any, None, None)];
+        assert_eq!(
+            render("tap0", &rules),
+            r#"table inet pecocloud_tap0 {}
+delete table inet pecocloud_tap0
+table inet pecocloud_tap0 {
+    chain forward {
+        type filter hook forward priority 0; policy accept;
+        iifname "tap0" ct state established,related accept
+        oifname "tap0" ct state established,related accept
+        oifname "tap0" ct status dnat accept
+        iifname "tap0" ip daddr 10.0.0.0/8 accept
+        iifname "tap0" drop
+        oifname "tap0" drop
+    }
+}
+"#
+        );
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     model::*, operation::*,
 };
 /// handler for the routes
-use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder, ResponseError};
 use std::sync::Mutex;
 
 #[get("/api/v1")]
@@ -12,8 +12,28 @@ async fn index() -> impl Responder {
 }
 
 #[get("/api/v1/vm")]
-async fn get_root_vm_page_handler() -> impl Responder {
-    HttpResponse::Ok().body("Index page of Vm management")
+async fn list_vm_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Query<VmListRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = list_vm_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/restore_all")]
+async fn restore_all_vm_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    _request: web::Json<VmRestoreAllRequest>,
+) -> impl Responder {
+    let res = restore_all_vm_op(pool).await;
+    match res {
+        Ok(infos) => HttpResponse::Ok().json(VmRestoreAllResponse { infos }),
+        Err(e) => e.error_response(),
+    }
 }
 
 #[post("/api/v1/vm")]
@@ -22,13 +42,13 @@ async fn create_vm_handler(
     request: web::Json<VmCreateRequest>,
 ) -> impl Responder {
     let request = request.into_inner();
-    let res = create_vm_op(pool, &request.config).await;
+    let res = create_vm_op(pool, &request.config, request.from_snapshot.as_ref()).await;
     match res {
         Ok(vmid) => HttpResponse::Ok().json(VmCreateResponse {
             vmid,
             created_at: chrono::Local::now(),
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -41,7 +61,7 @@ async fn get_vm_status_handler(
     let res = get_vm_status_op(pool, request.vmid).await;
     match res {
         Ok(info) => HttpResponse::Ok().json(info),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -57,7 +77,7 @@ async fn modify_metadata_handler(
             vmid: request.vmid,
             time: chrono::Local::now(),
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -78,7 +98,111 @@ async fn operate_vm_handler(
             vmid: request.vmid,
             time: chrono::Local::now(),
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[patch("/api/v1/vm/{vmid}/network/{iface_id}")]
+async fn update_network_rate_limit_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmNetworkRateLimitRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = update_network_rate_limit_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/volumes")]
+async fn attach_volume_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmVolumeAttachRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = attach_volume_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/api/v1/vm/{vmid}/volumes")]
+async fn detach_volume_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmVolumeDetachRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = detach_volume_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[patch("/api/v1/vm/{vmid}/volumes/{drive_id}")]
+async fn swap_volume_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmVolumeSwapRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = swap_volume_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[patch("/api/v1/vm/{vmid}/volumes/{drive_id}/rate_limit")]
+async fn update_drive_rate_limit_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmDriveRateLimitRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = update_drive_rate_limit_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/port_forward")]
+async fn create_port_forward_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmPortForwardRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = create_port_forward_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/security_group")]
+async fn attach_security_group_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmSecurityGroupRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = attach_security_group_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/api/v1/vm/{vmid}/security_group")]
+async fn detach_security_group_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmSecurityGroupRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = detach_security_group_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -94,7 +218,7 @@ async fn delete_vm_handler(
             vmid: request.vmid,
             time: chrono::Local::now(),
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -110,7 +234,36 @@ async fn create_vm_mem_snapshot_handler(
             vmid: request.vmid,
             vm_mem_snapshot_id: id,
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/api/v1/vm/{vmid}/vm_mem_snapshot")]
+async fn list_vm_mem_snapshot_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmSnapshotListRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = list_vm_mem_snapshot_op(pool, request.vmid).await;
+    match res {
+        Ok(snapshots) => HttpResponse::Ok().json(VmSnapshotListResponse {
+            vmid: request.vmid,
+            snapshots,
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/api/v1/vm/{vmid}/vm_mem_snapshot/{vm_mem_snapshot_id}")]
+async fn get_vm_mem_snapshot_detail_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmSnapshotDetailRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = get_vm_mem_snapshot_detail_op(pool, request.vmid, request.snapshot_id).await;
+    match res {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -126,6 +279,121 @@ async fn delete_vm_mem_snapshot_handler(
             vmid: request.vmid,
             vm_mem_snapshot_id: request.vm_mem_snapshot_id,
         }),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/vm_mem_snapshot/{vm_mem_snapshot_id}/restore")]
+async fn restore_vm_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmLoadSnapshotRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = restore_vm_op(pool, request.vmid, request.snapshot_id, request.resume).await;
+    match res {
+        Ok(_) => HttpResponse::Ok().json(VmLoadSnapshotResponse {
+            vmid: request.vmid,
+            snapshot_id: request.snapshot_id,
+            time: chrono::Local::now(),
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/disk_snapshot")]
+async fn create_disk_snapshot_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmCreateDiskSnapshotRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = create_disk_snapshot_op(pool, request.vmid).await;
+    match res {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/api/v1/vm/{vmid}/disk_snapshot")]
+async fn list_disk_snapshot_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmDiskSnapshotListRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = list_disk_snapshot_op(pool, request.vmid).await;
+    match res {
+        Ok(snapshots) => HttpResponse::Ok().json(VmDiskSnapshotListResponse {
+            vmid: request.vmid,
+            snapshots,
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/disk_snapshot/{snapshot_id}/rollback")]
+async fn rollback_disk_snapshot_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmDiskSnapshotRollbackRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = rollback_disk_snapshot_op(pool, request.vmid, request.snapshot_id).await;
+    match res {
+        Ok(_) => HttpResponse::Ok().json(VmDiskSnapshotRollbackResponse {
+            vmid: request.vmid,
+            snapshot_id: request.snapshot_id,
+            time: chrono::Local::now(),
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/vm/{vmid}/disk_snapshot/{snapshot_id}/publish")]
+async fn publish_disk_snapshot_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<VmPublishImageRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = publish_disk_snapshot_op(pool, request.vmid, request.snapshot_id).await;
+    match res {
+        Ok(image_id) => HttpResponse::Ok().json(VmPublishImageResponse {
+            vmid: request.vmid,
+            snapshot_id: request.snapshot_id,
+            image_id,
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/api/v1/security_group")]
+async fn create_security_group_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<SecurityGroupCreateRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = create_security_group_op(pool, &request).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/api/v1/security_group")]
+async fn list_security_group_handler(pool: web::Data<Mutex<VmPool>>) -> impl Responder {
+    let res = list_security_group_op(pool).await;
+    match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/api/v1/security_group")]
+async fn delete_security_group_handler(
+    pool: web::Data<Mutex<VmPool>>,
+    request: web::Json<SecurityGroupDeleteRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let res = delete_security_group_op(pool, request.id).await;
+    match res {
+        Ok(_) => HttpResponse::Ok().json(SecurityGroupDeleteResponse { id: request.id }),
+        Err(e) => e.error_response(),
     }
 }
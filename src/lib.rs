@@ -7,4 +7,8 @@ pub mod error;
 pub mod storage_models;
 pub mod kernel_mgr;
 pub mod storage_mgr;
-pub mod network_mgr;
\ No newline at end of file
+pub mod network_mgr;
+pub mod network_models;
+pub mod network_backend;
+pub mod firewall;
+pub mod client;
\ No newline at end of file
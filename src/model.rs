@@ -1,19 +1,33 @@
+use std::net::Ipv4Addr;
+
 use chrono::{DateTime, Local};
 use rustcracker::{
     components::machine::{Config, MachineCore},
-    model::{full_vm_configuration::FullVmConfiguration, instance_info::InstanceInfo},
+    model::{
+        drive::{CacheType, IoEngine},
+        full_vm_configuration::FullVmConfiguration,
+        instance_info::InstanceInfo,
+        rate_limiter::RateLimiter,
+    },
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::error::VmManageError;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ErrorResponse {
+    /// Stable machine-readable code, e.g. `VM_NOT_FOUND`
+    pub code: String,
     pub message: String,
+    /// Id of the failed request, also found in the logs of the manager
+    pub request_id: Uuid,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VmCreateRequest {
     pub config: MachineCreateConfig,
+    pub from_snapshot: Option<VmSnapshotSource>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +36,21 @@ pub struct VmCreateResponse {
     pub created_at: chrono::DateTime<Local>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmListRequest {
+    pub status: Option<String>,
+    pub kernel: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmListResponse {
+    pub vms: Vec<VmListItem>,
+    /// Pass as `cursor` to fetch the next page, `None` on the last page
+    pub next_cursor: Option<Uuid>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VmQueryStatusRequest {
     pub vmid: Uuid,
@@ -75,6 +104,14 @@ pub struct VmCreateSnapshotRequest {
 pub struct VmLoadSnapshotRequest {
     pub vmid: Uuid,
     pub snapshot_id: Uuid,
+    pub resume: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmLoadSnapshotResponse {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+    pub time: chrono::DateTime<Local>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -95,6 +132,17 @@ pub struct VmSnapshotDetailRequest {
     pub snapshot_id: Uuid,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSnapshotListRequest {
+    pub vmid: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSnapshotListResponse {
+    pub vmid: Uuid,
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VmRestoreAllRequest {}
 
@@ -126,6 +174,196 @@ pub struct VmDeleteVMMSResponse {
     pub vm_mem_snapshot_id: Uuid,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmCreateDiskSnapshotRequest {
+    pub vmid: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDiskSnapshotListRequest {
+    pub vmid: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDiskSnapshotListResponse {
+    pub vmid: Uuid,
+    pub snapshots: Vec<DiskSnapshotInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDiskSnapshotRollbackRequest {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDiskSnapshotRollbackResponse {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+    pub time: chrono::DateTime<Local>,
+}
+
+/// Publish the root volume of a disk snapshot as an image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmPublishImageRequest {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmPublishImageResponse {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+    pub image_id: Uuid,
+}
+
+/// Either `volume_id` of an existing volume or `size_in_mib` of a new one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeAttachRequest {
+    pub vmid: Uuid,
+    pub volume_id: Option<Uuid>,
+    pub size_in_mib: Option<i32>,
+    pub is_read_only: Option<bool>,
+    pub cache_type: Option<CacheType>,
+    pub io_engine: Option<IoEngine>,
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeAttachResponse {
+    pub vmid: Uuid,
+    pub volume_id: Uuid,
+    pub drive_id: String,
+    pub time: chrono::DateTime<Local>,
+}
+
+/// The volume is kept in storage unless `delete_volume` is set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeDetachRequest {
+    pub vmid: Uuid,
+    pub drive_id: String,
+    pub delete_volume: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeDetachResponse {
+    pub vmid: Uuid,
+    pub volume_id: Uuid,
+    pub drive_id: String,
+    pub time: chrono::DateTime<Local>,
+}
+
+/// `volume_id` is an existing volume attached to no vm. The old volume is
+/// kept in storage unless `delete_old_volume` is set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeSwapRequest {
+    pub vmid: Uuid,
+    pub drive_id: String,
+    pub volume_id: Uuid,
+    pub delete_old_volume: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmVolumeSwapResponse {
+    pub vmid: Uuid,
+    pub drive_id: String,
+    pub volume_id: Uuid,
+    pub old_volume_id: Uuid,
+    pub time: chrono::DateTime<Local>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDriveRateLimitRequest {
+    pub vmid: Uuid,
+    pub drive_id: String,
+    pub rate_limiter: RateLimiter,
+}
+
+/// Limits in effect after the update
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmDriveRateLimitResponse {
+    pub vmid: Uuid,
+    pub drive_id: String,
+    pub rate_limiter: Option<RateLimiter>,
+    pub time: chrono::DateTime<Local>,
+}
+
+/// Limits left out are kept as they are, a `clear_*` flag lifts one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmNetworkRateLimitRequest {
+    pub vmid: Uuid,
+    pub iface_id: String,
+    pub rx_rate_limiter: Option<RateLimiter>,
+    pub tx_rate_limiter: Option<RateLimiter>,
+    pub clear_rx_rate_limiter: Option<bool>,
+    pub clear_tx_rate_limiter: Option<bool>,
+}
+
+/// Limits in effect after the update
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmNetworkRateLimitResponse {
+    pub vmid: Uuid,
+    pub iface_id: String,
+    pub rx_rate_limiter: Option<RateLimiter>,
+    pub tx_rate_limiter: Option<RateLimiter>,
+    pub time: chrono::DateTime<Local>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupCreateRequest {
+    pub name: String,
+    pub rules: Vec<SecurityGroupRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupCreateResponse {
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupListResponse {
+    pub groups: Vec<SecurityGroup>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupDeleteRequest {
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupDeleteResponse {
+    pub id: Uuid,
+}
+
+/// Attach a security group to a vm, or detach it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSecurityGroupRequest {
+    pub vmid: Uuid,
+    pub group_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSecurityGroupResponse {
+    pub vmid: Uuid,
+    /// Groups attached to the vm after the request
+    pub group_ids: Vec<Uuid>,
+}
+
+/// Expose `guest_port` of the primary interface of a vm on a host port
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmPortForwardRequest {
+    pub vmid: Uuid,
+    pub protocol: ForwardProtocol,
+    pub guest_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmPortForwardResponse {
+    pub vmid: Uuid,
+    pub port_forward: PortForward,
+    pub time: DateTime<Local>,
+}
+
 // Schemas
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -135,8 +373,136 @@ pub struct MachineCreateConfig {
     pub kernel_name: String,
     pub kernel_version: String,
     pub enable_hyperthreading: Option<bool>,
+    /// JSON object served to the guest, `network` is reserved for the NIC leases
     pub initial_metadata: Option<String>,
-    pub volume_size_in_mib: i32,
+    /// Size of the root volume, required unless it defaults to the size of `image_id`
+    /// or of the root volume of the vm a clone is made from
+    pub volume_size_in_mib: Option<i32>,
+    /// Published image the root volume is a copy-on-write child of, blank if not given
+    pub image_id: Option<Uuid>,
+    /// Limits the bandwidth and IOPS of the root drive
+    pub root_drive_rate_limiter: Option<RateLimiter>,
+    /// Limits the traffic received by the guest
+    pub network_rx_rate_limiter: Option<RateLimiter>,
+    /// Limits the traffic sent by the guest
+    pub network_tx_rate_limiter: Option<RateLimiter>,
+    /// NICs of the vm, in guest order (eth0, eth1, ...). A single NIC on the default
+    /// network if not given. The rate limiters above apply to NICs that set none.
+    pub network_interfaces: Option<Vec<NetworkInterfaceConfig>>,
+    /// Run the vm in a network namespace of its own, joined to the host bridge
+    pub enable_netns: Option<bool>,
+}
+
+/// One NIC of a vm. Only eth0 gets its address on the kernel command line,
+/// the guest configures all of them from `network.interfaces` of its metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkInterfaceConfig {
+    /// Network to plug into, the default one of the pool if not given
+    pub network: Option<String>,
+    /// Fixed guest MAC, e.g. `06:00:AC:10:00:02`, derived if not given
+    pub guest_mac: Option<String>,
+    /// Fixed guest address, the guest end of a free /30 of the guest network
+    pub guest_ip: Option<Ipv4Addr>,
+    pub rx_rate_limiter: Option<RateLimiter>,
+    pub tx_rate_limiter: Option<RateLimiter>,
+}
+
+/// Vm/mem snapshot a new vm is cloned from. The clone runs in a network
+/// namespace of its own, with fresh leases for the NICs of its source.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmSnapshotSource {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleDirection {
+    /// Traffic toward the guest
+    Ingress,
+    /// Traffic from the guest
+    Egress,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Any,
+}
+
+/// Allow traffic of `protocol` from (ingress) or to (egress) `cidr`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroupRule {
+    pub direction: RuleDirection,
+    pub protocol: RuleProtocol,
+    /// Port range of tcp/udp rules, a single port if only one is given
+    pub port_from: Option<u16>,
+    pub port_to: Option<u16>,
+    /// e.g. `0.0.0.0/0`
+    pub cidr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+impl ForwardProtocol {
+    /// Name as understood by iptables, also the stored form
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardProtocol::Tcp => "tcp",
+            ForwardProtocol::Udp => "udp",
+        }
+    }
+}
+
+impl std::str::FromStr for ForwardProtocol {
+    type Err = VmManageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(ForwardProtocol::Tcp),
+            "udp" => Ok(ForwardProtocol::Udp),
+            _ => Err(VmManageError::InvalidArgument(format!(
+                "unknown protocol {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Snapshots of every volume of a vm taken together
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiskSnapshotInfo {
+    pub snapshot_id: Uuid,
+    pub volumes: Vec<VolumeSnapshot>,
+    pub created_at: DateTime<Local>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VolumeSnapshot {
+    pub volume_id: Uuid,
+    /// Id of the snapshot in the storage manager
+    pub volume_snapshot_id: Uuid,
+}
+
+/// Traffic to `host_port` of the host is DNATed to `guest_ip:guest_port`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PortForward {
+    pub protocol: ForwardProtocol,
+    pub host_port: u16,
+    pub guest_ip: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityGroup {
+    pub id: Uuid,
+    pub name: String,
+    pub rules: Vec<SecurityGroupRule>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -153,6 +519,16 @@ pub struct VmViewInfo {
     pub vm_info: InstanceInfo,
     pub full_config: FullVmConfiguration,
     pub boot_config: Config,
+    /// Addresses of the guest, one per network interface
+    pub guest_ips: Vec<Ipv4Addr>,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct VmListItem {
+    pub vmid: Uuid,
+    pub status: MachineState,
+    pub config: sqlx::types::Json<MachineCreateConfig>,
+    pub created_at: DateTime<Local>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -160,6 +536,11 @@ pub struct SnapshotInfo {
     pub snapshot_id: Uuid,
     pub snapshot_path: String,
     pub memory_path: String,
+    /// Size in bytes, `None` if the file is gone
+    pub snapshot_size: Option<u64>,
+    pub memory_size: Option<u64>,
+    /// State of the vm when the snapshot was taken
+    pub status: MachineState,
     pub created_at: DateTime<Local>,
 }
 
@@ -177,8 +558,109 @@ pub const PAUSED: MachineState = 3;
 pub const STOPPED: MachineState = 4;
 pub const DELETED: MachineState = 5;
 
+pub fn machine_state_name(state: MachineState) -> &'static str {
+    match state {
+        CREATED => "CREATED",
+        RUNNING => "RUNNING",
+        PAUSED => "PAUSED",
+        STOPPED => "STOPPED",
+        DELETED => "DELETED",
+        _ => "UNKNOWN",
+    }
+}
+
+pub fn machine_state_from_name(name: &str) -> Option<MachineState> {
+    match name {
+        "CREATED" => Some(CREATED),
+        "RUNNING" => Some(RUNNING),
+        "PAUSED" => Some(PAUSED),
+        "STOPPED" => Some(STOPPED),
+        "DELETED" => Some(DELETED),
+        _ => None,
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgVmMemSnapshotElement {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+    pub mem_file_path: String,
+    pub snapshot_path: String,
+    pub status: MachineState,
+    pub created_at: DateTime<Local>,
+}
+
 #[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
 pub struct PgVolumeElement {
     pub vmid: Uuid,
     pub volume_id: Uuid,
+    /// Firecracker drive backed by the volume
+    pub drive_id: String,
+    /// Image the volume was cloned from
+    pub image_id: Option<Uuid>,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgNetworkElement {
+    pub vmid: Uuid,
+    pub tap_id: i32,
+    pub guest_mac: String,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgIpLeaseElement {
+    pub vmid: Uuid,
+    pub tap_id: i32,
+    /// Network address of the leased subnet, e.g. `172.16.0.4`
+    pub subnet: String,
+    pub prefix_len: i32,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgSecurityGroupElement {
+    pub id: Uuid,
+    pub name: String,
+    pub rules: sqlx::types::Json<Vec<SecurityGroupRule>>,
+    pub created_at: DateTime<Local>,
+}
+
+impl From<PgSecurityGroupElement> for SecurityGroup {
+    fn from(element: PgSecurityGroupElement) -> Self {
+        SecurityGroup {
+            id: element.id,
+            name: element.name,
+            rules: element.rules.0,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgSecurityGroupBindingElement {
+    pub vmid: Uuid,
+    pub group_id: Uuid,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgPortForwardElement {
+    pub vmid: Uuid,
+    pub protocol: String,
+    pub host_port: i32,
+    pub guest_ip: String,
+    pub guest_port: i32,
+}
+
+/// A vm running in a network namespace of its own
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgNetnsElement {
+    pub vmid: Uuid,
+    pub transit_index: i32,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PgDiskSnapshotElement {
+    pub vmid: Uuid,
+    pub snapshot_id: Uuid,
+    pub volume_id: Uuid,
+    pub volume_snapshot_id: Uuid,
+    pub created_at: DateTime<Local>,
 }
@@ -0,0 +1,584 @@
+//! Host side of the microVM networking: tap devices, addresses and forwarding
+use std::{fs::OpenOptions, future::Future, net::Ipv4Addr, os::fd::AsRawFd};
+
+use futures::TryStreamExt;
+use run_script::ScriptOptions;
+use tokio::process::Command;
+
+use crate::{
+    error::{VmManageError, VmManageResult},
+    model::{ForwardProtocol, PortForward},
+};
+
+/// A tap device to set up on the host
+#[derive(Debug, Clone)]
+pub struct TapDevice {
+    pub name: String,
+    /// Address of the host end of the tap
+    pub host_ip: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Host interface the microVMs reach the outside through
+    pub host_iface: String,
+}
+
+pub trait NetworkBackend {
+    /// Create the tap, address it, bring it up and forward it to the host interface
+    fn create_tap(&self, tap: &TapDevice) -> impl Future<Output = VmManageResult<()>> + Send;
+
+    /// Remove the tap and its forwarding rules, succeeds if the tap is already gone
+    fn delete_tap(&self, tap: &TapDevice) -> impl Future<Output = VmManageResult<()>> + Send;
+}
+
+/// Backend chosen by `NETWORK_BACKEND`, `netlink` (default) or `shell`
+pub enum Backend {
+    Netlink(NetlinkBackend),
+    Shell(ShellBackend),
+}
+
+impl Backend {
+    pub fn from_env() -> Self {
+        match std::env::var("NETWORK_BACKEND").as_deref() {
+            Ok("shell") => Backend::Shell(ShellBackend),
+            Ok("netlink") | Err(_) => Backend::Netlink(NetlinkBackend),
+            Ok(other) => {
+                log::warn!("Unknown network backend {}, using netlink", other);
+                Backend::Netlink(NetlinkBackend)
+            }
+        }
+    }
+}
+
+impl NetworkBackend for Backend {
+    async fn create_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        match self {
+            Backend::Netlink(backend) => backend.create_tap(tap).await,
+            Backend::Shell(backend) => backend.create_tap(tap).await,
+        }
+    }
+
+    async fn delete_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        match self {
+            Backend::Netlink(backend) => backend.delete_tap(tap).await,
+            Backend::Shell(backend) => backend.delete_tap(tap).await,
+        }
+    }
+}
+
+fn network_error(e: impl std::error::Error + Send + Sync + 'static) -> VmManageError {
+    VmManageError::NetworkError(Box::new(e))
+}
+
+/* Native backend: tun ioctls for the device, rtnetlink for the link */
+
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+pub struct NetlinkBackend;
+
+impl NetlinkBackend {
+    /// Create a persistent tap device, like `ip tuntap add dev <name> mode tap`
+    fn add_tuntap(name: &str) -> VmManageResult<()> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(VmManageError::InvalidArgument(format!(
+                "tap name {} is too long",
+                name
+            )));
+        }
+        let tun = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+            .map_err(network_error)?;
+
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short,
+            _pad: [0; 22],
+        };
+        for (dst, src) in req.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        // SAFETY: `req` is a valid `ifreq` and the fd stays open during both calls
+        unsafe {
+            if libc::ioctl(tun.as_raw_fd(), libc::TUNSETIFF, &mut req) < 0 {
+                return Err(network_error(std::io::Error::last_os_error()));
+            }
+            if libc::ioctl(tun.as_raw_fd(), libc::TUNSETPERSIST, 1 as libc::c_int) < 0 {
+                return Err(network_error(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    fn connect() -> VmManageResult<rtnetlink::Handle> {
+        let (connection, handle, _) = rtnetlink::new_connection().map_err(network_error)?;
+        tokio::spawn(connection);
+        Ok(handle)
+    }
+
+    async fn link_index(handle: &rtnetlink::Handle, name: &str) -> VmManageResult<Option<u32>> {
+        let mut links = handle.link().get().match_name(name.to_string()).execute();
+        match links.try_next().await {
+            Ok(link) => Ok(link.map(|link| link.header.index)),
+            /* No such device */
+            Err(rtnetlink::Error::NetlinkError(e))
+                if e.code.map(|code| code.get()) == Some(-libc::ENODEV) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(network_error(e)),
+        }
+    }
+}
+
+impl NetworkBackend for NetlinkBackend {
+    async fn create_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        let handle = Self::connect()?;
+
+        /* Someone else's link, never ours to remove */
+        if Self::link_index(&handle, &tap.name).await?.is_some() {
+            return Err(VmManageError::TapInUse(tap.name.to_owned()));
+        }
+
+        /* Opening the clone device and the ioctls block */
+        let name = tap.name.to_owned();
+        tokio::task::spawn_blocking(move || Self::add_tuntap(&name))
+            .await
+            .map_err(network_error)??;
+        let index = Self::link_index(&handle, &tap.name).await?.ok_or_else(|| {
+            VmManageError::NetworkError(format!("tap {} vanished", tap.name).into())
+        })?;
+        handle
+            .address()
+            .add(index, tap.host_ip.into(), tap.prefix_len)
+            .execute()
+            .await
+            .map_err(network_error)?;
+        handle
+            .link()
+            .set(index)
+            .up()
+            .execute()
+            .await
+            .map_err(network_error)?;
+
+        enable_ip_forward().await?;
+        add_forward_rules(tap).await
+    }
+
+    async fn delete_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        delete_forward_rules(tap).await?;
+
+        let handle = Self::connect()?;
+        if let Some(index) = Self::link_index(&handle, &tap.name).await? {
+            handle
+                .link()
+                .del(index)
+                .execute()
+                .await
+                .map_err(network_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a link of that name exists in the namespace of the manager
+pub async fn link_exists(name: &str) -> bool {
+    tokio::fs::try_exists(format!("/sys/class/net/{}", name))
+        .await
+        .unwrap_or(false)
+}
+
+async fn enable_ip_forward() -> VmManageResult<()> {
+    tokio::fs::write("/proc/sys/net/ipv4/ip_forward", "1")
+        .await
+        .map_err(network_error)
+}
+
+async fn iptables(args: &[&str]) -> VmManageResult<bool> {
+    let output = Command::new("iptables")
+        .args(args)
+        .output()
+        .await
+        .map_err(network_error)?;
+    Ok(output.status.success())
+}
+
+/// Insert a rule at `position` unless it is already there
+async fn ensure_rule(table: &str, position: &[&str], rule: &[&str]) -> VmManageResult<()> {
+    let check = [&["-t", table, "-C"], &position[..1], rule].concat();
+    if iptables(&check).await? {
+        return Ok(());
+    }
+    let insert = [&["-t", table, "-I"], position, rule].concat();
+    match iptables(&insert).await? {
+        true => Ok(()),
+        false => Err(VmManageError::NetworkError(
+            format!("iptables {} failed", insert.join(" ")).into(),
+        )),
+    }
+}
+
+async fn add_forward_rules(tap: &TapDevice) -> VmManageResult<()> {
+    ensure_rule(
+        "nat",
+        &["POSTROUTING", "1"],
+        &["-o", &tap.host_iface, "-j", "MASQUERADE"],
+    )
+    .await?;
+    ensure_rule(
+        "filter",
+        &["FORWARD", "1"],
+        &[
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "ACCEPT",
+        ],
+    )
+    .await?;
+    ensure_rule(
+        "filter",
+        &["FORWARD", "1"],
+        &["-i", &tap.name, "-o", &tap.host_iface, "-j", "ACCEPT"],
+    )
+    .await
+}
+
+async fn delete_forward_rules(tap: &TapDevice) -> VmManageResult<()> {
+    let rule = ["-i", &tap.name, "-o", &tap.host_iface, "-j", "ACCEPT"];
+    /* Delete every copy, a missing rule is not an error */
+    while iptables(&[&["-t", "filter", "-D", "FORWARD"], &rule[..]].concat()).await? {}
+    Ok(())
+}
+
+/* Port forwards: DNAT on the host, whatever backend made the tap */
+
+fn port_forward_rules(forward: &PortForward) -> [(&'static str, &'static str, Vec<String>); 2] {
+    let protocol = forward.protocol.as_str().to_string();
+    [
+        (
+            "nat",
+            "PREROUTING",
+            vec![
+                "-p".to_string(),
+                protocol.clone(),
+                "--dport".to_string(),
+                forward.host_port.to_string(),
+                "-j".to_string(),
+                "DNAT".to_string(),
+                "--to-destination".to_string(),
+                format!("{}:{}", forward.guest_ip, forward.guest_port),
+            ],
+        ),
+        (
+            "filter",
+            "FORWARD",
+            vec![
+                "-p".to_string(),
+                protocol,
+                "-d".to_string(),
+                forward.guest_ip.to_string(),
+                "--dport".to_string(),
+                forward.guest_port.to_string(),
+                "-j".to_string(),
+                "ACCEPT".to_string(),
+            ],
+        ),
+    ]
+}
+
+/// Whether nothing on the host listens on `port`, probed by binding it
+pub fn host_port_free(protocol: ForwardProtocol, port: u16) -> bool {
+    let addr = (Ipv4Addr::UNSPECIFIED, port);
+    match protocol {
+        ForwardProtocol::Tcp => std::net::TcpListener::bind(addr).is_ok(),
+        ForwardProtocol::Udp => std::net::UdpSocket::bind(addr).is_ok(),
+    }
+}
+
+/// Install the DNAT toward the guest and let the rewritten traffic through
+pub async fn add_port_forward(forward: &PortForward) -> VmManageResult<()> {
+    enable_ip_forward().await?;
+    for (table, chain, rule) in port_forward_rules(forward) {
+        let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+        ensure_rule(table, &[chain, "1"], &rule).await?;
+    }
+    Ok(())
+}
+
+/// Remove the rules of a port forward, succeeds if they are already gone
+pub async fn delete_port_forward(forward: &PortForward) -> VmManageResult<()> {
+    for (table, chain, rule) in port_forward_rules(forward) {
+        let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+        while iptables(&[&["-t", table, "-D", chain], &rule[..]].concat()).await? {}
+    }
+    Ok(())
+}
+
+/* Per-vm network namespaces: the taps live inside, a veth joins the host bridge */
+
+/// Namespace end of the veth of every vm namespace
+const NETNS_VETH: &str = "veth0";
+
+/// Network namespace a vm runs in
+#[derive(Debug, Clone)]
+pub struct Netns {
+    pub name: String,
+    /// Host end of the veth, enslaved to the bridge
+    pub veth_host: String,
+    /// Address of the namespace end of the veth, the route of the guests toward it
+    pub veth_ip: Ipv4Addr,
+    pub bridge: String,
+    pub bridge_ip: Ipv4Addr,
+    /// Prefix of the transit network of the bridge and the veths
+    pub prefix_len: u8,
+    /// Host interface the microVMs reach the outside through
+    pub host_iface: String,
+}
+
+async fn ip_cmd(args: &[&str]) -> VmManageResult<std::process::Output> {
+    Command::new("ip")
+        .args(args)
+        .output()
+        .await
+        .map_err(network_error)
+}
+
+async fn ip(args: &[&str]) -> VmManageResult<()> {
+    let output = ip_cmd(args).await?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(VmManageError::NetworkError(
+            format!(
+                "ip {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+        )),
+    }
+}
+
+async fn ensure_bridge(ns: &Netns) -> VmManageResult<()> {
+    if !ip_cmd(&["link", "show", &ns.bridge])
+        .await?
+        .status
+        .success()
+    {
+        ip(&["link", "add", "name", &ns.bridge, "type", "bridge"]).await?;
+        ip(&[
+            "addr",
+            "add",
+            &format!("{}/{}", ns.bridge_ip, ns.prefix_len),
+            "dev",
+            &ns.bridge,
+        ])
+        .await?;
+    }
+    ip(&["link", "set", &ns.bridge, "up"]).await?;
+
+    enable_ip_forward().await?;
+    ensure_rule(
+        "nat",
+        &["POSTROUTING", "1"],
+        &["-o", &ns.host_iface, "-j", "MASQUERADE"],
+    )
+    .await?;
+    ensure_rule(
+        "filter",
+        &["FORWARD", "1"],
+        &[
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "ACCEPT",
+        ],
+    )
+    .await?;
+    ensure_rule(
+        "filter",
+        &["FORWARD", "1"],
+        &["-i", &ns.bridge, "-o", &ns.host_iface, "-j", "ACCEPT"],
+    )
+    .await
+}
+
+/// Create the namespace with its veth to the host bridge, the bridge on first use
+pub async fn create_netns(ns: &Netns) -> VmManageResult<()> {
+    ensure_bridge(ns).await?;
+
+    /* Drop a stale namespace of the same name */
+    ip_cmd(&["netns", "del", &ns.name]).await?;
+    ip(&["netns", "add", &ns.name]).await?;
+    ip(&[
+        "link",
+        "add",
+        &ns.veth_host,
+        "type",
+        "veth",
+        "peer",
+        "name",
+        NETNS_VETH,
+        "netns",
+        &ns.name,
+    ])
+    .await?;
+    ip(&["link", "set", &ns.veth_host, "master", &ns.bridge, "up"]).await?;
+    ip(&["-n", &ns.name, "link", "set", "lo", "up"]).await?;
+    ip(&[
+        "-n",
+        &ns.name,
+        "addr",
+        "add",
+        &format!("{}/{}", ns.veth_ip, ns.prefix_len),
+        "dev",
+        NETNS_VETH,
+    ])
+    .await?;
+    ip(&["-n", &ns.name, "link", "set", NETNS_VETH, "up"]).await?;
+    ip(&[
+        "-n",
+        &ns.name,
+        "route",
+        "add",
+        "default",
+        "via",
+        &ns.bridge_ip.to_string(),
+    ])
+    .await?;
+    ip(&[
+        "netns",
+        "exec",
+        &ns.name,
+        "sysctl",
+        "-qw",
+        "net.ipv4.ip_forward=1",
+    ])
+    .await
+}
+
+/// Move a tap created on the host into the namespace, where it goes by `name`,
+/// and route its subnet there
+pub async fn move_tap_into_netns(tap: &TapDevice, ns: &Netns, name: &str) -> VmManageResult<()> {
+    let mask = u32::MAX
+        .checked_shl(32 - tap.prefix_len as u32)
+        .unwrap_or(0);
+    let subnet = Ipv4Addr::from(u32::from(tap.host_ip) & mask);
+
+    /* Addresses do not survive the move */
+    ip(&["link", "set", &tap.name, "netns", &ns.name, "name", name]).await?;
+    ip(&[
+        "-n",
+        &ns.name,
+        "addr",
+        "add",
+        &format!("{}/{}", tap.host_ip, tap.prefix_len),
+        "dev",
+        name,
+    ])
+    .await?;
+    ip(&["-n", &ns.name, "link", "set", name, "up"]).await?;
+    ip(&[
+        "route",
+        "replace",
+        &format!("{}/{}", subnet, tap.prefix_len),
+        "via",
+        &ns.veth_ip.to_string(),
+    ])
+    .await
+}
+
+/// Delete the namespace with the taps and the veth inside, succeeds if it is already gone
+pub async fn delete_netns(ns: &Netns) -> VmManageResult<()> {
+    /* The routes toward the namespace outlive its veth */
+    ip_cmd(&["route", "flush", "via", &ns.veth_ip.to_string()]).await?;
+    ip_cmd(&["netns", "del", &ns.name]).await?;
+    ip_cmd(&["link", "del", &ns.veth_host]).await?;
+    Ok(())
+}
+
+/* Fallback backend: the original `ip`/`iptables` script */
+
+pub struct ShellBackend;
+
+impl ShellBackend {
+    /// Run `script` off the async workers, it waits for the shell to exit
+    async fn run(script: &'static str, tap: &TapDevice) -> VmManageResult<()> {
+        let args = vec![
+            tap.name.to_owned(),
+            tap.host_iface.to_owned(),
+            format!("{}/{}", tap.host_ip, tap.prefix_len),
+        ];
+        let (code, _output, error) = tokio::task::spawn_blocking(move || {
+            run_script::run_script!(script, &args, &ScriptOptions::new())
+        })
+        .await
+        .map_err(network_error)?
+        .map_err(network_error)?;
+        match code {
+            0 => Ok(()),
+            _ => Err(VmManageError::NetworkError(
+                format!("{} script exited with {}: {}", tap.name, code, error.trim()).into(),
+            )),
+        }
+    }
+}
+
+impl NetworkBackend for ShellBackend {
+    async fn create_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        if link_exists(&tap.name).await {
+            return Err(VmManageError::TapInUse(tap.name.to_owned()));
+        }
+        Self::run(
+            r#"
+            set -e
+            TAP_DEV="$1"
+            HOST_IFACE="$2"
+            TAP_ADDR="$3"
+
+            # Setup network interface
+            sudo ip tuntap add dev "$TAP_DEV" mode tap
+            sudo ip addr add "$TAP_ADDR" dev "$TAP_DEV"
+            sudo ip link set dev "$TAP_DEV" up
+
+            # Enable ip forwarding
+            sudo sh -c "echo 1 > /proc/sys/net/ipv4/ip_forward"
+
+            # Set up microVM internet access
+            sudo iptables -t nat -D POSTROUTING -o "$HOST_IFACE" -j MASQUERADE || true
+            sudo iptables -D FORWARD -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT \
+                || true
+            sudo iptables -D FORWARD -i "$TAP_DEV" -o "$HOST_IFACE" -j ACCEPT || true
+            sudo iptables -t nat -A POSTROUTING -o "$HOST_IFACE" -j MASQUERADE
+            sudo iptables -I FORWARD 1 -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT
+            sudo iptables -I FORWARD 1 -i "$TAP_DEV" -o "$HOST_IFACE" -j ACCEPT
+            "#,
+            tap,
+        )
+        .await
+    }
+
+    async fn delete_tap(&self, tap: &TapDevice) -> VmManageResult<()> {
+        Self::run(
+            r#"
+            TAP_DEV="$1"
+            HOST_IFACE="$2"
+
+            sudo iptables -D FORWARD -i "$TAP_DEV" -o "$HOST_IFACE" -j ACCEPT || true
+            sudo ip link del "$TAP_DEV" 2> /dev/null || true
+            "#,
+            tap,
+        )
+        .await
+    }
+}
@@ -1,8 +1,18 @@
 //! RPC to network management (naive with http)
-use run_script::ScriptOptions;
-use rustcracker::model::network_interface::NetworkInterface;
+use std::{net::Ipv4Addr, str::FromStr};
 
-use crate::error::{VmManageError, VmManageResult};
+use reqwest::Response;
+use rustcracker::model::{network_interface::NetworkInterface, rate_limiter::RateLimiter};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::{
+    error::{VmManageError, VmManageResult},
+    model::NetworkInterfaceConfig,
+    network_backend::{move_tap_into_netns, Backend, Netns, NetworkBackend, TapDevice},
+    network_models::*,
+    pool::VmPool,
+};
 
 /* 
     Manage network configuration.
@@ -36,53 +46,669 @@ Provides:
     (No crate with full functionality managing OVN for now.)
 */
 
+/// Name of the guest network of the pool, the only one local backends know
+pub const DEFAULT_NETWORK: &str = "default";
 
-fn mock(tap_id: u32) -> VmManageResult<()> {
-    let (_code, _output, _error) = run_script::run_script!(
-        r#"
-        TAP_DEV="tap$1"
-        HOST_IFACE="eth$1"
-        TAP_IP="172.16.0.1"
-        MASK_SHORT="/30"
-
-        # Setup network interface
-        sudo ip link del "$TAP_DEV" 2> /dev/null || true
-        sudo ip tuntap add dev "$TAP_DEV" mode tap
-        sudo ip addr add "${TAP_IP}${MASK_SHORT}" dev "$TAP_DEV"
-        sudo ip link set dev "$TAP_DEV" up
-
-        # Enable ip forwarding
-        sudo sh -c "echo 1 > /proc/sys/net/ipv4/ip_forward"
-
-        # Set up microVM internet access
-        sudo iptables -t nat -D POSTROUTING -o "$HOST_IFACE" -j MASQUERADE || true
-        sudo iptables -D FORWARD -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT \
-            || true
-        sudo iptables -D FORWARD -i "$TAP_DEV" -o "$HOST_IFACE" -j ACCEPT || true
-        sudo iptables -t nat -A POSTROUTING -o "$HOST_IFACE" -j MASQUERADE
-        sudo iptables -I FORWARD 1 -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT
-        sudo iptables -I FORWARD 1 -i "$TAP_DEV" -o "$HOST_IFACE" -j ACCEPT
-        "#,
-        &vec![format!("{}", tap_id)],
-        &ScriptOptions::new()
-    ).map_err(|_| VmManageError::NetworkError)?;
-    Ok(())
+/// Host interface the microVMs reach the outside through
+fn host_iface() -> String {
+    std::env::var("HOST_IFACE").unwrap_or_else(|_| "eth0".to_string())
+}
+
+/// An IPv4 network, e.g. `172.16.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = Self::mask(self.prefix_len);
+        u32::from(addr) & mask == u32::from(self.addr) & mask
+    }
+
+    /// Subnets of `prefix_len` carved out of this network, in address order
+    pub fn subnets(&self, prefix_len: u8) -> impl Iterator<Item = Ipv4Addr> {
+        let start = u32::from(self.addr) & Self::mask(self.prefix_len);
+        /* None fit into a network narrower than them */
+        let count = match prefix_len.checked_sub(self.prefix_len) {
+            Some(bits) => 1u64 << bits,
+            None => 0,
+        };
+        let step = 1u64 << (32 - prefix_len as u32);
+        (0..count).map(move |i| Ipv4Addr::from((start as u64 + i * step) as u32))
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = VmManageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmManageError::InvalidArgument(format!("invalid cidr {}", s));
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr = addr.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.parse::<u8>().map_err(|_| invalid())?;
+        if prefix_len > 32 {
+            return Err(invalid());
+        }
+        Ok(Ipv4Cidr { addr, prefix_len })
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Every tap leases a /30: network, host end, guest end, broadcast
+pub const LEASE_PREFIX_LEN: u8 = 30;
+
+/// Subnet leased to one tap of a vm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpLease {
+    pub subnet: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl IpLease {
+    pub fn host_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.subnet) + 1)
+    }
+
+    pub fn guest_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.subnet) + 2)
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(Ipv4Cidr::mask(self.prefix_len))
+    }
+
+    /// Kernel `ip=` argument configuring `iface` of the guest with this lease
+    pub fn kernel_ip_arg(&self, iface: &str) -> String {
+        format!(
+            "ip={}::{}:{}::{}:off",
+            self.guest_ip(),
+            self.host_ip(),
+            self.netmask(),
+            iface
+        )
+    }
+
+    /// Metadata entry the guest configures `iface` from, the kernel command
+    /// line only has room for one interface
+    pub fn metadata(&self, iface: &str, guest_mac: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "name": iface,
+            "mac": guest_mac,
+            "address": format!("{}/{}", self.guest_ip(), self.prefix_len),
+            "gateway": self.host_ip().to_string(),
+        })
+    }
+}
+
+/// Pick the lowest /30 of `network` not in `leased`
+pub fn allocate_lease(network: &Ipv4Cidr, leased: &[Ipv4Addr]) -> VmManageResult<IpLease> {
+    network
+        .subnets(LEASE_PREFIX_LEN)
+        .find(|subnet| !leased.contains(subnet))
+        .map(|subnet| IpLease {
+            subnet,
+            prefix_len: LEASE_PREFIX_LEN,
+        })
+        .ok_or_else(|| {
+            VmManageError::NetworkError(format!("guest network {} exhausted", network).into())
+        })
+}
+
+/// The /30 of `network` whose guest end is `guest_ip`, if nobody leased it yet
+pub fn lease_of_guest_ip(
+    network: &Ipv4Cidr,
+    guest_ip: Ipv4Addr,
+    leased: &[Ipv4Addr],
+) -> VmManageResult<IpLease> {
+    let lease = IpLease {
+        subnet: Ipv4Addr::from(u32::from(guest_ip) & Ipv4Cidr::mask(LEASE_PREFIX_LEN)),
+        prefix_len: LEASE_PREFIX_LEN,
+    };
+    if !network.contains(lease.subnet) || lease.guest_ip() != guest_ip {
+        return Err(VmManageError::InvalidArgument(format!(
+            "{} is not a guest address of {}",
+            guest_ip, network
+        )));
+    }
+    if leased.contains(&lease.subnet) {
+        return Err(VmManageError::InvalidArgument(format!(
+            "{} is already leased",
+            guest_ip
+        )));
+    }
+    Ok(lease)
+}
+
+/// Inclusive range of host ports handed out to port forwards, e.g. `20000-29999`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = VmManageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmManageError::InvalidArgument(format!("invalid port range {}", s));
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = start.parse::<u16>().map_err(|_| invalid())?;
+        let end = end.parse::<u16>().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Pick the lowest port of `range` not already forwarded
+pub fn allocate_port(range: &PortRange, taken: &[u16]) -> VmManageResult<u16> {
+    (range.start..=range.end)
+        .find(|port| !taken.contains(port))
+        .ok_or_else(|| {
+            VmManageError::NetworkError(format!("port range {} exhausted", range).into())
+        })
+}
+
+/// A guest MAC address, always unicast and locally administered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr([u8; 6]);
+
+impl MacAddr {
+    /// Derive the address of a tap of a vm, `salt` picks another one on collision.
+    /// FNV-1a keeps the address stable across builds and toolchains.
+    pub fn derive(pool_id: Uuid, vmid: Uuid, tap_id: u32, salt: u32) -> Self {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        let hash = [
+            &pool_id.as_bytes()[..],
+            &vmid.as_bytes()[..],
+            &tap_id.to_be_bytes()[..],
+            &salt.to_be_bytes()[..],
+        ]
+        .concat()
+        .into_iter()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+        .to_be_bytes();
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&hash[..6]);
+        /* Locally administered unicast: set bit 1, clear bit 0 of the first octet */
+        bytes[0] = (bytes[0] | 0x02) & !0x01;
+        MacAddr(bytes)
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = VmManageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmManageError::InvalidArgument(format!("invalid mac address {}", s));
+        let mut bytes = [0u8; 6];
+        let mut octets = s.split(':');
+        for byte in bytes.iter_mut() {
+            let octet = octets.next().ok_or_else(invalid)?;
+            if octet.len() != 2 {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(octet, 16).map_err(|_| invalid())?;
+        }
+        if octets.next().is_some() || bytes[0] & 0x01 != 0 || bytes[0] & 0x02 == 0 {
+            return Err(invalid());
+        }
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
+/// Derive a mac for a tap of a vm not in `taken`
+pub fn allocate_mac(pool_id: Uuid, vmid: Uuid, tap_id: u32, taken: &[MacAddr]) -> MacAddr {
+    (0..)
+        .map(|salt| MacAddr::derive(pool_id, vmid, tap_id, salt))
+        .find(|mac| !taken.contains(mac))
+        .unwrap_or_else(|| MacAddr::derive(pool_id, vmid, tap_id, 0))
+}
+
+/// Host-global name of a tap, the ids are allocated host-wide
+pub fn tap_name(tap_id: u32) -> String {
+    format!("tap{tap_id}")
 }
 
+fn tap_device(tap_id: u32, lease: &IpLease) -> TapDevice {
+    TapDevice {
+        name: tap_name(tap_id),
+        host_ip: lease.host_ip(),
+        prefix_len: lease.prefix_len,
+        host_iface: host_iface(),
+    }
+}
+
+/// Bridge joining the namespaces of the vms to the host
+fn host_bridge() -> String {
+    std::env::var("HOST_BRIDGE").unwrap_or_else(|_| "pecobr0".to_string())
+}
+
+/// Namespace of a vm. `index`, allocated host-wide, picks its veth and its
+/// address on the transit network, the bridge takes the first one.
+pub fn netns(pool: &VmPool, vmid: Uuid, index: u32) -> VmManageResult<Netns> {
+    let transit = pool.netns_transit;
+    let base = u32::from(transit.addr) & Ipv4Cidr::mask(transit.prefix_len);
+    let size = 1u64 << (32 - transit.prefix_len as u32);
+    /* Network, bridge, ..., broadcast */
+    if index as u64 + 3 >= size {
+        return Err(VmManageError::NetworkError(
+            format!("transit network {} exhausted", transit).into(),
+        ));
+    }
+    Ok(Netns {
+        name: format!("pecocloud-{}", vmid),
+        veth_host: format!("vh{}", index),
+        veth_ip: Ipv4Addr::from(base + 2 + index),
+        bridge: host_bridge(),
+        bridge_ip: Ipv4Addr::from(base + 1),
+        prefix_len: transit.prefix_len,
+        host_iface: host_iface(),
+    })
+}
+
+/// Whether taps are provisioned by the remote network manager
+fn remote() -> bool {
+    std::env::var("NETWORK_BACKEND").as_deref() == Ok("remote")
+}
+
+/// Decode an answer of the network manager, a failure becomes `UpstreamStatus`
+/// carrying the message of its `ErrorResponse`
+async fn parse<T: DeserializeOwned>(res: Response) -> VmManageResult<T> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res.json::<T>().await?);
+    }
+    let body = res.text().await?;
+    let message = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(e) => Some(e.message),
+        Err(_) => Some(body).filter(|body| !body.is_empty()),
+    };
+    Err(VmManageError::UpstreamStatus(
+        "network mgr".to_string(),
+        status,
+        message,
+    ))
+}
+
+pub async fn create_netif(
+    pool: &mut VmPool,
+    req: &NetifCreateRequest,
+) -> VmManageResult<NetifCreateResponse> {
+    let url = format!("{}{}", pool.network_mgr_addr, "/api/v1/netif");
+    let res = pool.network_client.post(url).json(req).send().await?;
+    let res = parse::<NetifCreateResponse>(res).await?;
+
+    Ok(res)
+}
+
+pub async fn delete_netif(pool: &mut VmPool, vmid: Uuid, tap_id: u32) -> VmManageResult<u32> {
+    let url = format!("{}{}", pool.network_mgr_addr, "/api/v1/netif");
+    let req = NetifDeleteRequest { vmid, tap_id };
+    let res = pool.network_client.delete(url).json(&req).send().await?;
+    let res = parse::<NetifDeleteResponse>(res).await?;
+
+    Ok(res.tap_id)
+}
+
+pub async fn update_netif(pool: &mut VmPool, req: &NetifUpdateRequest) -> VmManageResult<u32> {
+    let url = format!("{}{}", pool.network_mgr_addr, "/api/v1/netif");
+    let res = pool.network_client.patch(url).json(req).send().await?;
+    let res = parse::<NetifUpdateResponse>(res).await?;
+
+    Ok(res.tap_id)
+}
+
+/// Provision tap `tap_id` for `nic`, its guest end configured with `lease` and `guest_mac`,
+/// inside `netns` if the vm has one, under the name given along with it
+pub async fn create_network_interface(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    tap_id: u32,
+    nic: &NetworkInterfaceConfig,
+    lease: &IpLease,
+    guest_mac: MacAddr,
+    netns: Option<(&Netns, &str)>,
+) -> VmManageResult<NetworkInterface> {
+    let tap = tap_device(tap_id, lease);
+    let network = nic.network.to_owned();
+    let (host_dev_name, guest_mac) = match remote() {
+        true if netns.is_some() => {
+            return Err(VmManageError::InvalidArgument(
+                "network namespaces are not supported by the remote network manager".to_string(),
+            ))
+        }
+        true => {
+            let req = NetifCreateRequest {
+                vmid,
+                tap_id,
+                network,
+                guest_mac: guest_mac.to_string(),
+                subnet: lease.subnet,
+                prefix_len: lease.prefix_len,
+            };
+            let res = create_netif(pool, &req).await?;
+            (res.host_dev_name, res.guest_mac)
+        }
+        false => {
+            if let Some(network) = network.filter(|network| network != DEFAULT_NETWORK) {
+                return Err(VmManageError::InvalidArgument(format!(
+                    "unknown network {}, only {} is served locally",
+                    network, DEFAULT_NETWORK
+                )));
+            }
+            Backend::from_env().create_tap(&tap).await?;
+            match netns {
+                Some((netns, name)) => {
+                    if let Err(e) = move_tap_into_netns(&tap, netns, name).await {
+                        let _ = Backend::from_env().delete_tap(&tap).await;
+                        return Err(e);
+                    }
+                    (name.to_string(), guest_mac.to_string())
+                }
+                None => (tap.name, guest_mac.to_string()),
+            }
+        }
+    };
 
-pub async fn create_network_interface(tap_id: u32) -> VmManageResult<NetworkInterface> {
-    // let url = format!("{}{}", pool.network_mgr_addr, "/api/v1/netif");
-    mock(tap_id)?;
-    let host_dev_name = format!("tap{tap_id}");
-    
     let net_if = NetworkInterface {
-        // Fixed in rootfs.
-        guest_mac: Some(format!("AA:FC:00:00:00:0{}", tap_id)),
+        guest_mac: Some(guest_mac),
         iface_id: tap_id.to_string(),
         host_dev_name: host_dev_name.into(),
-        rx_rate_limiter: None,
-        tx_rate_limiter: None,
+        rx_rate_limiter: nic.rx_rate_limiter.to_owned(),
+        tx_rate_limiter: nic.tx_rate_limiter.to_owned(),
     };
 
     Ok(net_if)
-}
\ No newline at end of file
+}
+
+/// Hand the new rate limits of a tap to the network manager if it provisioned
+/// the tap, firecracker alone enforces them otherwise
+pub async fn update_network_interface(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    iface_id: &str,
+    rx_rate_limiter: Option<RateLimiter>,
+    tx_rate_limiter: Option<RateLimiter>,
+) -> VmManageResult<()> {
+    if !remote() {
+        return Ok(());
+    }
+
+    /* The remote backend names the devices after their taps */
+    let tap_id = iface_id
+        .parse::<u32>()
+        .map_err(|_| VmManageError::NetworkInterfaceNotFound(vmid, iface_id.to_string()))?;
+    let req = NetifUpdateRequest {
+        vmid,
+        tap_id,
+        rx_rate_limiter,
+        tx_rate_limiter,
+    };
+    update_netif(pool, &req).await?;
+    Ok(())
+}
+
+pub async fn delete_network_interface(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    tap_id: u32,
+) -> VmManageResult<()> {
+    if remote() {
+        delete_netif(pool, vmid, tap_id).await?;
+        return Ok(());
+    }
+
+    /* Only the name matters to tear the tap down */
+    let tap = TapDevice {
+        name: tap_name(tap_id),
+        host_ip: Ipv4Addr::UNSPECIFIED,
+        prefix_len: 0,
+        host_iface: host_iface(),
+    };
+    Backend::from_env().delete_tap(&tap).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn port_range_parse() {
+        assert_eq!(
+            "20000-29999".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 20000,
+                end: 29999
+            }
+        );
+        assert_eq!("80-80".parse::<PortRange>().unwrap().to_string(), "80-80");
+        assert_eq!(
+            "1-65535".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 1,
+                end: 65535
+            }
+        );
+        for bad in [
+            /* Inverted */
+            "29999-20000",
+            /* Port 0 is never forwarded */
+            "0-100",
+            "0-0",
+            "20000",
+            "20000-",
+            "-20000",
+            "20000-65536",
+            "a-b",
+            "",
+        ] {
+            assert!(bad.parse::<PortRange>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn allocate_port_takes_lowest_free_and_exhausts() {
+        let range = PortRange {
+            start: 20000,
+            end: 20002,
+        };
+        assert_eq!(allocate_port(&range, &[]).unwrap(), 20000);
+        assert_eq!(allocate_port(&range, &[20000]).unwrap(), 20001);
+        assert_eq!(allocate_port(&range, &[20000, 20001]).unwrap(), 20002);
+        assert_eq!(allocate_port(&range, &[20001, 19999]).unwrap(), 20000);
+        assert!(allocate_port(&range, &[20000, 20001, 20002]).is_err());
+
+        let single = PortRange {
+            start: 65535,
+            end: 65535,
+        };
+        assert_eq!(allocate_port(&single, &[]).unwrap(), 65535);
+        assert!(allocate_port(&single, &[65535]).is_err());
+    }
+
+    #[test]
+    fn mac_derive_is_stable() {
+        let pool_id = Uuid::nil();
+        let vmid = Uuid::from_u128(1);
+        let mac = MacAddr::derive(pool_id, vmid, 0, 0);
+        assert_eq!(mac, MacAddr::derive(pool_id, vmid, 0, 0));
+        assert_eq!(mac.to_string(), "AE:6C:48:AF:5C:5B");
+        assert_ne!(mac, MacAddr::derive(pool_id, vmid, 0, 1));
+        assert_ne!(mac, MacAddr::derive(pool_id, vmid, 1, 0));
+        assert_ne!(mac, MacAddr::derive(Uuid::from_u128(2), vmid, 0, 0));
+    }
+
+    #[test]
+    fn mac_derive_is_unicast_and_locally_administered() {
+        for tap_id in 0..64 {
+            for salt in 0..16 {
+                let MacAddr(bytes) = MacAddr::derive(
+                    Uuid::from_u128(7),
+                    Uuid::from_u128(tap_id as u128),
+                    tap_id,
+                    salt,
+                );
+                assert_eq!(bytes[0] & 0x01, 0, "multicast bit set");
+                assert_eq!(bytes[0] & 0x02, 0x02, "local bit clear");
+            }
+        }
+    }
+
+    #[test]
+    fn allocate_mac_skips_taken() {
+        let (pool_id, vmid) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let first = allocate_mac(pool_id, vmid, 0, &[]);
+        assert_eq!(first, MacAddr::derive(pool_id, vmid, 0, 0));
+        let second = allocate_mac(pool_id, vmid, 0, &[first]);
+        assert_eq!(second, MacAddr::derive(pool_id, vmid, 0, 1));
+    }
+
+    #[test]
+    fn mac_parse() {
+        let mac: MacAddr = "06:00:ac:10:00:02".parse().unwrap();
+        assert_eq!(mac, MacAddr([0x06, 0x00, 0xac, 0x10, 0x00, 0x02]));
+        assert_eq!(mac.to_string(), "06:00:AC:10:00:02");
+        assert_eq!(mac.to_string().parse::<MacAddr>().unwrap(), mac);
+        for bad in [
+            /* Multicast */
+            "07:00:AC:10:00:02",
+            /* Globally administered */
+            "04:00:AC:10:00:02",
+            "FF:FF:FF:FF:FF:FF",
+            "06:00:AC:10:00",
+            "06:00:AC:10:00:02:03",
+            "06:00:AC:10:00:2",
+            "06:00:AC:10:00:002",
+            "06:00:AC:10:00:G2",
+            "06-00-AC-10-00-02",
+            "",
+        ] {
+            assert!(bad.parse::<MacAddr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            cidr("172.16.0.0/16"),
+            Ipv4Cidr {
+                addr: ip("172.16.0.0"),
+                prefix_len: 16
+            }
+        );
+        assert_eq!(cidr("0.0.0.0/0").prefix_len, 0);
+        assert_eq!(cidr("10.0.0.1/32").prefix_len, 32);
+        for bad in [
+            "172.16.0.0",
+            "172.16.0.0/33",
+            "172.16.0/16",
+            "172.16.0.0/",
+            "/16",
+        ] {
+            assert!(bad.parse::<Ipv4Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn cidr_contains_network_and_broadcast() {
+        let network = cidr("172.16.0.0/16");
+        assert!(network.contains(ip("172.16.0.0")));
+        assert!(network.contains(ip("172.16.255.255")));
+        assert!(!network.contains(ip("172.15.255.255")));
+        assert!(!network.contains(ip("172.17.0.0")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+    }
+
+    #[test]
+    fn subnets_are_aligned() {
+        /* A host address in the network part is masked off */
+        let subnets: Vec<Ipv4Addr> = cidr("10.0.0.5/29").subnets(30).collect();
+        assert_eq!(subnets, vec![ip("10.0.0.0"), ip("10.0.0.4")]);
+        assert_eq!(cidr("10.0.0.0/30").subnets(30).count(), 1);
+        assert_eq!(cidr("10.0.0.0/31").subnets(30).count(), 0);
+        assert_eq!(cidr("10.0.0.0/16").subnets(30).count(), 1 << 14);
+    }
+
+    #[test]
+    fn lease_layout() {
+        let lease = allocate_lease(&cidr("172.16.0.0/16"), &[]).unwrap();
+        assert_eq!(lease.subnet, ip("172.16.0.0"));
+        assert_eq!(lease.prefix_len, LEASE_PREFIX_LEN);
+        assert_eq!(lease.host_ip(), ip("172.16.0.1"));
+        assert_eq!(lease.guest_ip(), ip("172.16.0.2"));
+        assert_eq!(lease.netmask(), ip("255.255.255.252"));
+        assert_eq!(
+            lease.kernel_ip_arg("eth0"),
+            "ip=172.16.0.2::172.16.0.1:255.255.255.252::eth0:off"
+        );
+    }
+
+    #[test]
+    fn allocate_lease_skips_leased_and_exhausts() {
+        let network = cidr("172.16.0.0/29");
+        let first = allocate_lease(&network, &[]).unwrap();
+        let second = allocate_lease(&network, &[first.subnet]).unwrap();
+        assert_eq!(second.subnet, ip("172.16.0.4"));
+        assert!(allocate_lease(&network, &[first.subnet, second.subnet]).is_err());
+        /* A freed lease is handed out again */
+        assert_eq!(
+            allocate_lease(&network, &[second.subnet]).unwrap().subnet,
+            ip("172.16.0.0")
+        );
+        assert!(allocate_lease(&cidr("172.16.0.0/31"), &[]).is_err());
+    }
+
+    #[test]
+    fn lease_of_guest_ip_only_takes_guest_ends() {
+        let network = cidr("172.16.0.0/16");
+        let lease = lease_of_guest_ip(&network, ip("172.16.0.6"), &[]).unwrap();
+        assert_eq!(lease.subnet, ip("172.16.0.4"));
+        /* Network, host end and broadcast of a /30 */
+        for bad in ["172.16.0.4", "172.16.0.5", "172.16.0.7"] {
+            assert!(
+                lease_of_guest_ip(&network, ip(bad), &[]).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(lease_of_guest_ip(&network, ip("172.17.0.2"), &[]).is_err());
+        assert!(lease_of_guest_ip(&network, ip("172.16.0.6"), &[ip("172.16.0.4")]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Models for network interface related requests and responses
+use std::net::Ipv4Addr;
+
+use rustcracker::model::rate_limiter::RateLimiter;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifCreateRequest {
+    pub vmid: Uuid,
+    pub tap_id: u32,
+    /// Network to plug the tap into, the default one if not given
+    pub network: Option<String>,
+    pub guest_mac: String,
+    /// Leased subnet, the host end takes its first address
+    pub subnet: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifCreateResponse {
+    pub host_dev_name: String,
+    pub guest_mac: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifDeleteRequest {
+    pub vmid: Uuid,
+    pub tap_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifDeleteResponse {
+    pub tap_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifUpdateRequest {
+    pub vmid: Uuid,
+    pub tap_id: u32,
+    pub rx_rate_limiter: Option<RateLimiter>,
+    pub tx_rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetifUpdateResponse {
+    pub tap_id: u32,
+}
@@ -1,10 +1,11 @@
 use std::sync::Mutex;
 
 use actix_web::web;
+use rustcracker::model::rate_limiter::RateLimiter;
 use uuid::Uuid;
 
 use crate::{
-    error::VmManageResult,
+    error::{VmManageError, VmManageResult},
     model::*,
     pool::{self, VmPool},
 };
@@ -12,13 +13,19 @@ use crate::{
 pub async fn create_vm_op(
     pool: web::Data<Mutex<VmPool>>,
     create_config: &MachineCreateConfig,
+    from_snapshot: Option<&VmSnapshotSource>,
 ) -> VmManageResult<Uuid> {
     let mut pool_mutex = pool.lock().unwrap();
     let vmid = Uuid::new_v4();
     let mut pool_guard = pool_mutex.lock(vmid).await?;
     let pool = pool_guard.pool();
 
-    pool::create_vm(pool, vmid, create_config).await?;
+    match from_snapshot {
+        Some(source) => {
+            pool::create_vm_from_vm_mem_snapshot(pool, vmid, create_config, source).await?
+        }
+        None => pool::create_vm(pool, vmid, create_config).await?,
+    };
 
     Ok(vmid)
 }
@@ -87,6 +94,222 @@ pub async fn modify_metadata_op(
     Ok(())
 }
 
+/// None keeps the limiter, Some(None) lifts it, Some(Some(..)) replaces it
+fn rate_limit_update(
+    rate_limiter: &Option<RateLimiter>,
+    clear: Option<bool>,
+) -> VmManageResult<Option<Option<RateLimiter>>> {
+    match (rate_limiter, clear == Some(true)) {
+        (Some(_), true) => Err(VmManageError::InvalidArgument(
+            "a rate limiter cannot be both set and cleared".to_string(),
+        )),
+        (None, true) => Ok(Some(None)),
+        (rate_limiter, false) => Ok(rate_limiter.to_owned().map(Some)),
+    }
+}
+
+pub async fn update_network_rate_limit_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmNetworkRateLimitRequest,
+) -> VmManageResult<VmNetworkRateLimitResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let network_interface = pool::update_network_rate_limit(
+        pool,
+        vmid,
+        &request.iface_id,
+        rate_limit_update(&request.rx_rate_limiter, request.clear_rx_rate_limiter)?,
+        rate_limit_update(&request.tx_rate_limiter, request.clear_tx_rate_limiter)?,
+    )
+    .await?;
+
+    Ok(VmNetworkRateLimitResponse {
+        vmid,
+        iface_id: network_interface.iface_id,
+        rx_rate_limiter: network_interface.rx_rate_limiter,
+        tx_rate_limiter: network_interface.tx_rate_limiter,
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn attach_volume_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmVolumeAttachRequest,
+) -> VmManageResult<VmVolumeAttachResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let (volume_id, drive_id) = pool::attach_vm_volume(pool, request).await?;
+
+    Ok(VmVolumeAttachResponse {
+        vmid,
+        volume_id,
+        drive_id,
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn detach_volume_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmVolumeDetachRequest,
+) -> VmManageResult<VmVolumeDetachResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let volume_id = pool::detach_vm_volume(
+        pool,
+        vmid,
+        &request.drive_id,
+        request.delete_volume.unwrap_or(false),
+    )
+    .await?;
+
+    Ok(VmVolumeDetachResponse {
+        vmid,
+        volume_id,
+        drive_id: request.drive_id.to_owned(),
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn swap_volume_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmVolumeSwapRequest,
+) -> VmManageResult<VmVolumeSwapResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let old_volume_id = pool::swap_vm_volume(
+        pool,
+        vmid,
+        &request.drive_id,
+        request.volume_id,
+        request.delete_old_volume.unwrap_or(false),
+    )
+    .await?;
+
+    Ok(VmVolumeSwapResponse {
+        vmid,
+        drive_id: request.drive_id.to_owned(),
+        volume_id: request.volume_id,
+        old_volume_id,
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn update_drive_rate_limit_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmDriveRateLimitRequest,
+) -> VmManageResult<VmDriveRateLimitResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let drive = pool::update_drive_rate_limit(
+        pool,
+        vmid,
+        &request.drive_id,
+        request.rate_limiter.to_owned(),
+    )
+    .await?;
+
+    Ok(VmDriveRateLimitResponse {
+        vmid,
+        drive_id: drive.drive_id,
+        rate_limiter: drive.rate_limiter,
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn create_port_forward_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmPortForwardRequest,
+) -> VmManageResult<VmPortForwardResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let port_forward =
+        pool::create_port_forward(pool, vmid, request.protocol, request.guest_port).await?;
+
+    Ok(VmPortForwardResponse {
+        vmid,
+        port_forward,
+        time: chrono::Local::now(),
+    })
+}
+
+pub async fn create_security_group_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &SecurityGroupCreateRequest,
+) -> VmManageResult<SecurityGroupCreateResponse> {
+    let mut pool = pool.lock().unwrap();
+
+    let id = pool::create_security_group(&mut pool, &request.name, &request.rules).await?;
+
+    Ok(SecurityGroupCreateResponse { id })
+}
+
+pub async fn list_security_group_op(
+    pool: web::Data<Mutex<VmPool>>,
+) -> VmManageResult<SecurityGroupListResponse> {
+    let mut pool = pool.lock().unwrap();
+
+    let groups = pool::list_security_group(&mut pool).await?;
+
+    Ok(SecurityGroupListResponse { groups })
+}
+
+pub async fn delete_security_group_op(
+    pool: web::Data<Mutex<VmPool>>,
+    id: Uuid,
+) -> VmManageResult<()> {
+    let mut pool = pool.lock().unwrap();
+
+    pool::delete_security_group(&mut pool, id).await?;
+
+    Ok(())
+}
+
+pub async fn attach_security_group_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmSecurityGroupRequest,
+) -> VmManageResult<VmSecurityGroupResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let group_ids = pool::attach_security_group(pool, vmid, request.group_id).await?;
+
+    Ok(VmSecurityGroupResponse { vmid, group_ids })
+}
+
+pub async fn detach_security_group_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmSecurityGroupRequest,
+) -> VmManageResult<VmSecurityGroupResponse> {
+    let vmid = request.vmid;
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let group_ids = pool::detach_security_group(pool, vmid, request.group_id).await?;
+
+    Ok(VmSecurityGroupResponse { vmid, group_ids })
+}
+
 pub async fn get_vm_status_op(
     pool: web::Data<Mutex<VmPool>>,
     vmid: Uuid,
@@ -100,6 +323,25 @@ pub async fn get_vm_status_op(
     Ok(infos)
 }
 
+pub async fn list_vm_op(
+    pool: web::Data<Mutex<VmPool>>,
+    request: &VmListRequest,
+) -> VmManageResult<VmListResponse> {
+    let mut pool = pool.lock().unwrap();
+
+    let response = pool::list_vm(&mut pool, request).await?;
+
+    Ok(response)
+}
+
+pub async fn restore_all_vm_op(pool: web::Data<Mutex<VmPool>>) -> VmManageResult<Vec<Uuid>> {
+    let mut pool = pool.lock().unwrap();
+
+    let vmids = pool::restore_all_vm(&mut pool).await?;
+
+    Ok(vmids)
+}
+
 pub async fn create_vm_mem_snapshot_op(pool: web::Data<Mutex<VmPool>>, vmid: Uuid) -> VmManageResult<Uuid> {
     let mut pool_mutex = pool.lock().unwrap();
     let mut pool_guard = pool_mutex.lock(vmid).await?;
@@ -110,6 +352,33 @@ pub async fn create_vm_mem_snapshot_op(pool: web::Data<Mutex<VmPool>>, vmid: Uui
     Ok(vm_mem_snapshot_id)
 }
 
+pub async fn list_vm_mem_snapshot_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+) -> VmManageResult<Vec<SnapshotInfo>> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let snapshots = pool::list_vm_mem_snapshot(pool, vmid).await?;
+
+    Ok(snapshots)
+}
+
+pub async fn get_vm_mem_snapshot_detail_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+    vm_mem_snapshot_id: Uuid,
+) -> VmManageResult<SnapshotInfo> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let info = pool::get_vm_mem_snapshot_detail(pool, vmid, vm_mem_snapshot_id).await?;
+
+    Ok(info)
+}
+
 pub async fn delete_vm_mem_snapshot_op(pool: web::Data<Mutex<VmPool>>, vmid: Uuid, vm_mem_snapshot_id: Uuid) -> VmManageResult<()> {
     let mut pool_mutex = pool.lock().unwrap();
     let mut pool_guard = pool_mutex.lock(vmid).await?;
@@ -117,4 +386,71 @@ pub async fn delete_vm_mem_snapshot_op(pool: web::Data<Mutex<VmPool>>, vmid: Uui
 
     pool::delete_vm_mem_snapshot(pool, vmid, vm_mem_snapshot_id).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn restore_vm_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+    vm_mem_snapshot_id: Uuid,
+    resume: bool,
+) -> VmManageResult<()> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    pool::restore_vm_from_vm_mem_snapshot(pool, vmid, vm_mem_snapshot_id, resume).await?;
+    Ok(())
+}
+
+pub async fn create_disk_snapshot_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+) -> VmManageResult<DiskSnapshotInfo> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let info = pool::create_disk_snapshot(pool, vmid).await?;
+
+    Ok(info)
+}
+
+pub async fn list_disk_snapshot_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+) -> VmManageResult<Vec<DiskSnapshotInfo>> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let snapshots = pool::list_disk_snapshot(pool, vmid).await?;
+
+    Ok(snapshots)
+}
+
+pub async fn rollback_disk_snapshot_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+    snapshot_id: Uuid,
+) -> VmManageResult<()> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    pool::rollback_disk_snapshot(pool, vmid, snapshot_id).await?;
+    Ok(())
+}
+
+pub async fn publish_disk_snapshot_op(
+    pool: web::Data<Mutex<VmPool>>,
+    vmid: Uuid,
+    snapshot_id: Uuid,
+) -> VmManageResult<Uuid> {
+    let mut pool_mutex = pool.lock().unwrap();
+    let mut pool_guard = pool_mutex.lock(vmid).await?;
+    let pool = pool_guard.pool();
+
+    let image_id = pool::publish_disk_snapshot(pool, vmid, snapshot_id).await?;
+
+    Ok(image_id)
+}
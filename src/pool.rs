@@ -1,20 +1,186 @@
-use std::{env, path::PathBuf};
+use std::{env, net::Ipv4Addr, path::PathBuf, time::Duration};
 
 use rustcracker::{
-    components::machine::{Config, Machine, MachineCore},
-    model::{drive::Drive, logger::LogLevel, machine_configuration::MachineConfiguration},
+    components::{
+        agent::Agent,
+        machine::{Config, Machine, MachineCore, MachineError},
+    },
+    model::{
+        drive::Drive,
+        instance_info::State,
+        logger::LogLevel,
+        machine_configuration::MachineConfiguration,
+        network_interface::NetworkInterface,
+        partial_drive::PartialDrive,
+        rate_limiter::{RateLimiter, RateLimiterSet},
+        snapshot_load_params::SnapshotLoadParams,
+        token_bucket::TokenBucket,
+    },
+    utils::{
+        DEFAULT_FIRECRACKER_INIT_TIMEOUT_SECONDS, DEFAULT_FIRECRACKER_REQUEST_TIMEOUT_SECONDS,
+    },
 };
 use sqlx::postgres;
 use uuid::Uuid;
 
 use crate::{
     error::{VmManageError, VmManageResult},
+    firewall,
     kernel_mgr::get_kernel_image_path,
     model::*,
+    network_backend::{
+        add_port_forward, create_netns, delete_netns, delete_port_forward, host_port_free,
+        link_exists, Netns,
+    },
+    network_mgr::{
+        allocate_lease, allocate_mac, allocate_port, create_network_interface,
+        delete_network_interface, lease_of_guest_ip, netns, tap_name, update_network_interface,
+        IpLease, Ipv4Cidr, MacAddr, PortRange, LEASE_PREFIX_LEN,
+    },
     sql::*,
     storage_mgr::*,
 };
 
+/// Page size of vm listing when the request does not give one
+const DEFAULT_LIST_LIMIT: i64 = 100;
+
+/// Boot args every vm gets, the `ip=` of its lease is appended
+const DEFAULT_KERNEL_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+
+/// Network the guest /30 leases are carved from
+const DEFAULT_GUEST_NETWORK: &str = "172.16.0.0/16";
+
+/// Network the bridge and the veths of the vm namespaces are addressed from
+const DEFAULT_NETNS_TRANSIT: &str = "10.200.0.0/16";
+
+/// Host ports port forwards are allocated from
+const DEFAULT_PORT_FORWARD_RANGE: &str = "20000-29999";
+
+/// Where the pool id generated on the first start is kept
+const DEFAULT_POOL_ID_FILE: &str = "/var/lib/pecocloud/pool_id";
+
+/// How long to wait for a signalled firecracker process to exit, per signal
+const VMM_EXIT_POLLS: u32 = 50;
+const VMM_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Drive id of the root volume, data volumes get `data0`, `data1`, ...
+const ROOT_DRIVE_ID: &str = "rootfs";
+
+/// Lifecycle operations of a vm.
+/// CREATED -> RUNNING <-> PAUSED, RUNNING -> STOPPED, any -> DELETED.
+#[derive(Debug, Clone, Copy)]
+pub enum Transition {
+    Start,
+    Pause,
+    Resume,
+    Stop,
+    Delete,
+    Restore,
+}
+
+impl Transition {
+    /// States the operation could be applied in
+    fn allowed_states(self) -> &'static [MachineState] {
+        match self {
+            Transition::Start => &[CREATED],
+            Transition::Pause => &[RUNNING],
+            Transition::Resume => &[PAUSED],
+            Transition::Stop => &[RUNNING],
+            // DELETED: retry a deletion that failed halfway
+            Transition::Delete => &[CREATED, RUNNING, PAUSED, STOPPED, DELETED],
+            Transition::Restore => &[CREATED, RUNNING, PAUSED, STOPPED],
+        }
+    }
+
+    /// State of the vm after the operation
+    fn target_state(self) -> MachineState {
+        match self {
+            // Restore: PAUSED unless asked to resume
+            Transition::Start | Transition::Resume | Transition::Restore => RUNNING,
+            Transition::Pause => PAUSED,
+            Transition::Stop => STOPPED,
+            Transition::Delete => DELETED,
+        }
+    }
+}
+
+impl std::fmt::Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Transition::Start => "start",
+            Transition::Pause => "pause",
+            Transition::Resume => "resume",
+            Transition::Stop => "stop",
+            Transition::Delete => "delete",
+            Transition::Restore => "restore",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Compensating action of a completed step of creating or deleting a vm
+enum Undo {
+    DeleteVolume(Uuid),
+    DetachVolume(Uuid),
+    AttachVolume(Uuid),
+    DeleteTap(Uuid, u32),
+    RemoveFirewall(Option<String>, String),
+    DeleteNetns(Netns),
+    /// pid of a firecracker process
+    KillVmm(u32),
+}
+
+impl std::fmt::Display for Undo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Undo::DeleteVolume(volume) => write!(f, "delete volume {}", volume),
+            Undo::DetachVolume(volume) => write!(f, "detach volume {}", volume),
+            Undo::AttachVolume(volume) => write!(f, "attach volume {}", volume),
+            Undo::DeleteTap(vmid, tap_id) => write!(f, "delete tap{} of vm {}", tap_id, vmid),
+            Undo::RemoveFirewall(_, host_dev_name) => {
+                write!(f, "remove firewall of {}", host_dev_name)
+            }
+            Undo::DeleteNetns(netns) => write!(f, "delete network namespace {}", netns.name),
+            Undo::KillVmm(pid) => write!(f, "kill firecracker process {}", pid),
+        }
+    }
+}
+
+/// Undo actions registered by the completed steps of an operation,
+/// run in reverse order when a later step fails.
+/// Database writes are not registered, they live in a transaction instead.
+#[derive(Default)]
+struct Rollback {
+    undos: Vec<Undo>,
+}
+
+impl Rollback {
+    fn push(&mut self, undo: Undo) {
+        self.undos.push(undo);
+    }
+
+    async fn run(self, pool: &mut VmPool) {
+        for undo in self.undos.into_iter().rev() {
+            log::trace!("Rolling back: {}", undo);
+            let step = undo.to_string();
+            let res = match undo {
+                Undo::DeleteVolume(volume) => delete_volume(pool, volume).await.map(|_| ()),
+                Undo::DetachVolume(volume) => detach_volume(pool, volume).await.map(|_| ()),
+                Undo::AttachVolume(volume) => attach_volume(pool, volume).await.map(|_| ()),
+                Undo::DeleteTap(vmid, tap_id) => delete_network_interface(pool, vmid, tap_id).await,
+                Undo::RemoveFirewall(netns, host_dev_name) => {
+                    firewall::remove(netns.as_deref(), &host_dev_name).await
+                }
+                Undo::DeleteNetns(netns) => delete_netns(&netns).await,
+                Undo::KillVmm(pid) => kill_vmm(pid).await,
+            };
+            if let Err(e) = res {
+                log::error!("Fail to roll back ({}): {}", step, e.report());
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct VmPool {
     pub pool_id: Uuid,
@@ -24,6 +190,9 @@ pub struct VmPool {
     pub storage_client: reqwest::Client,
     pub network_mgr_addr: String,
     pub network_client: reqwest::Client,
+    pub guest_network: Ipv4Cidr,
+    pub port_forward_range: PortRange,
+    pub netns_transit: Ipv4Cidr,
     pub socket_dir: PathBuf,
     pub logs_dir: PathBuf,
     pub metrics_dir: PathBuf,
@@ -41,7 +210,7 @@ impl<'a> Drop for VmPoolGuard<'a> {
             let mut pool = self.pool.clone();
             tokio::spawn(async move {
                 if let Err(e) = release_vm_lock(&mut pool, &lock).await {
-                    log::error!("Failed to release lock {}: {}", lock, e)
+                    log::error!("Failed to release lock {}: {}", lock, e.report())
                 }
             });
         }
@@ -61,6 +230,26 @@ impl<'a> VmPoolGuard<'a> {
     }
 }
 
+/// Stable identity of the pool: `POOL_ID` if set, or else the id generated on
+/// the first start of the host and kept in `POOL_ID_FILE`
+async fn pool_id() -> VmManageResult<Uuid> {
+    if let Ok(pool_id) = env::var("POOL_ID") {
+        return Uuid::parse_str(&pool_id).map_err(|_| VmManageError::EnvPoolId);
+    }
+    let path = PathBuf::from(env::var("POOL_ID_FILE").unwrap_or(DEFAULT_POOL_ID_FILE.to_string()));
+    if let Ok(pool_id) = tokio::fs::read_to_string(&path).await {
+        return Uuid::parse_str(pool_id.trim()).map_err(|_| VmManageError::EnvPoolId);
+    }
+
+    let pool_id = Uuid::new_v4();
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(&path, pool_id.to_string()).await?;
+    log::info!("Generated pool id {} into {}", pool_id, path.display());
+    Ok(pool_id)
+}
+
 impl VmPool {
     pub async fn new() -> VmManageResult<VmPool> {
         let socket_dir =
@@ -93,7 +282,7 @@ impl VmPool {
             .max_connections(10)
             .connect(&database_url)
             .await
-            .map_err(|_| VmManageError::DBConnection)?;
+            .map_err(VmManageError::DBConnection)?;
 
         let etcd_config = etcd_client::ConnectOptions::new().with_user(etcd_user, etcd_password);
         let etcd_client = etcd_client::Client::connect([etcd_url], Some(etcd_config)).await?;
@@ -102,7 +291,23 @@ impl VmPool {
         let storage_client = reqwest::Client::new();
         let network_mgr_addr = env::var("NETWORK_MGR_ADDR").expect("NETWORK_MGR_ADDR must be set");
         let network_client = reqwest::Client::new();
-        let pool_id = Uuid::new_v4();
+        let guest_network = env::var("GUEST_NETWORK_CIDR")
+            .unwrap_or(DEFAULT_GUEST_NETWORK.to_string())
+            .parse::<Ipv4Cidr>()
+            .ok()
+            .filter(|cidr| cidr.prefix_len <= LEASE_PREFIX_LEN)
+            .ok_or(VmManageError::EnvGuestNetwork)?;
+        let port_forward_range = env::var("PORT_FORWARD_RANGE")
+            .unwrap_or(DEFAULT_PORT_FORWARD_RANGE.to_string())
+            .parse::<PortRange>()
+            .map_err(|_| VmManageError::EnvPortForwardRange)?;
+        let netns_transit = env::var("NETNS_TRANSIT_CIDR")
+            .unwrap_or(DEFAULT_NETNS_TRANSIT.to_string())
+            .parse::<Ipv4Cidr>()
+            .ok()
+            .filter(|cidr| cidr.prefix_len <= LEASE_PREFIX_LEN)
+            .ok_or(VmManageError::EnvNetnsTransit)?;
+        let pool_id = pool_id().await?;
 
         let pool = VmPool {
             pool_id,
@@ -112,6 +317,9 @@ impl VmPool {
             storage_client,
             network_mgr_addr,
             network_client,
+            guest_network,
+            port_forward_range,
+            netns_transit,
             socket_dir,
             logs_dir,
             metrics_dir,
@@ -169,6 +377,62 @@ impl VmPool {
         )
     }
 
+    /// Tap names are host-global, shared by every pool like the port forwards
+    #[inline]
+    fn network_storage_table(&self) -> String {
+        std::env::var(NETWORK_TABLE_NAME).unwrap_or(DEFAULT_NETWORK_TABLE.to_string())
+    }
+
+    /// Pools of a host may share a guest network and the host routes toward
+    /// every lease, so the leases are host-wide like the taps
+    #[inline]
+    fn ip_lease_storage_table(&self) -> String {
+        std::env::var(IP_LEASE_TABLE_NAME).unwrap_or(DEFAULT_IP_LEASE_TABLE.to_string())
+    }
+
+    #[inline]
+    fn security_group_storage_table(&self) -> String {
+        format!(
+            "{}_{}",
+            std::env::var(SECURITY_GROUP_TABLE_NAME)
+                .unwrap_or(DEFAULT_SECURITY_GROUP_TABLE.to_string()),
+            self.pool_id
+        )
+    }
+
+    #[inline]
+    fn security_group_binding_storage_table(&self) -> String {
+        format!(
+            "{}_{}",
+            std::env::var(SECURITY_GROUP_BINDING_TABLE_NAME)
+                .unwrap_or(DEFAULT_SECURITY_GROUP_BINDING_TABLE.to_string()),
+            self.pool_id
+        )
+    }
+
+    /// DNAT rules are host-wide, so unlike the other tables this one is
+    /// shared by every pool rather than suffixed with the pool id
+    #[inline]
+    fn port_forward_storage_table(&self) -> String {
+        std::env::var(PORT_FORWARD_TABLE_NAME).unwrap_or(DEFAULT_PORT_FORWARD_TABLE.to_string())
+    }
+
+    #[inline]
+    fn disk_snapshot_storage_table(&self) -> String {
+        format!(
+            "{}_{}",
+            std::env::var(DISK_SNAPSHOT_TABLE_NAME)
+                .unwrap_or(DEFAULT_DISK_SNAPSHOT_TABLE.to_string()),
+            self.pool_id
+        )
+    }
+
+    /// Veths and transit addresses are host-global, like the taps
+    #[inline]
+    fn netns_storage_table(&self) -> String {
+        std::env::var(NETNS_TABLE_NAME).unwrap_or(DEFAULT_NETNS_TABLE.to_string())
+    }
+
     #[inline]
     fn socket_path(&self, vmid: Uuid) -> PathBuf {
         self.socket_dir
@@ -215,13 +479,54 @@ impl VmPool {
             .bind(vmid)
             .fetch_one(&self.conn)
             .await
-            .map_err(|_| VmManageError::DBFetching)?;
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => VmManageError::VmNotFound(vmid),
+                e => VmManageError::DBFetching(e),
+            })?;
         let core = core.core.0;
         Ok(core)
     }
 
+    async fn get_status_db(&self, vmid: Uuid) -> VmManageResult<MachineState> {
+        log::trace!("Getting status of {} from database", vmid);
+        let element = sqlx::query_as::<_, PgMachineCoreElement>(GET_MACHINE_CORE_BY_VMID)
+            .bind(self.machine_core_storage_table())
+            .bind(vmid)
+            .fetch_one(&self.conn)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => VmManageError::VmNotFound(vmid),
+                e => VmManageError::DBFetching(e),
+            })?;
+        Ok(element.status)
+    }
+
+    /// Fail with `InvalidTransition` unless `transition` is legal in the recorded state
+    async fn check_transition(&self, vmid: Uuid, transition: Transition) -> VmManageResult<()> {
+        let status = self.get_status_db(vmid).await?;
+        match transition.allowed_states().contains(&status) {
+            true => Ok(()),
+            false => Err(VmManageError::InvalidTransition(
+                vmid,
+                transition.to_string(),
+                status,
+            )),
+        }
+    }
+
+    async fn get_core_all_db(&self) -> VmManageResult<Vec<PgMachineCoreElement>> {
+        log::trace!("Getting all cores from database");
+        let elements = sqlx::query_as::<_, PgMachineCoreElement>(GET_MACHINE_CORE_ALL)
+            .bind(self.machine_core_storage_table())
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+        Ok(elements)
+    }
+
     async fn add_core_db(
         &self,
+        conn: &mut postgres::PgConnection,
         vmid: Uuid,
         core: &MachineCore,
         status: MachineState,
@@ -233,22 +538,46 @@ impl VmPool {
             .bind(vmid)
             .bind(sqlx::types::Json(core.to_owned()))
             .bind(sqlx::types::Json(status))
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBInsertion)?;
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
 
+    /// `update_core_db` within a transaction
+    async fn update_core_tx_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        core: &MachineCore,
+        status: MachineState,
+    ) -> VmManageResult<()> {
+        log::trace!("Updating core of {} in transaction", vmid);
+        sqlx::query(UPDATE_MACHINE_CORE_BY_VMID)
+            .bind(self.machine_core_storage_table())
+            .bind(sqlx::types::Json(core))
+            .bind(status)
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBUpdating)?;
         Ok(())
     }
 
-    async fn delete_core_db(&self, vmid: Uuid) -> VmManageResult<()> {
+    async fn delete_core_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
         log::trace!("Deleting core of {} from database", vmid);
         let machine_core_storage_table = self.machine_core_storage_table();
         sqlx::query(DELETE_MACHINE_CORE_BY_VMID)
             .bind(machine_core_storage_table)
             .bind(vmid)
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBDeleting)?;
+            .map_err(VmManageError::DBDeleting)?;
         Ok(())
     }
 
@@ -266,12 +595,34 @@ impl VmPool {
             .bind(vmid)
             .execute(&self.conn)
             .await
-            .map_err(|_| VmManageError::DBUpdating)?;
+            .map_err(VmManageError::DBUpdating)?;
         Ok(())
     }
 
+    async fn list_vm_db(
+        &self,
+        status: Option<MachineState>,
+        kernel: Option<&String>,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> VmManageResult<Vec<VmListItem>> {
+        log::trace!("Listing vms from database");
+        let items = sqlx::query_as::<_, VmListItem>(LIST_MACHINE_WITH_CONFIG)
+            .bind(self.machine_core_storage_table())
+            .bind(self.config_storage_table())
+            .bind(status)
+            .bind(kernel)
+            .bind(cursor)
+            .bind(limit)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+        Ok(items)
+    }
+
     async fn add_create_config_db(
         &self,
+        conn: &mut postgres::PgConnection,
         vmid: Uuid,
         config: &MachineCreateConfig,
     ) -> VmManageResult<()> {
@@ -281,63 +632,112 @@ impl VmPool {
             .bind(config_storage_table)
             .bind(vmid)
             .bind(sqlx::types::Json(config))
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBInsertion)?;
+            .map_err(VmManageError::DBInsertion)?;
 
         Ok(())
     }
 
-    async fn delete_create_config_db(&self, vmid: Uuid) -> VmManageResult<()> {
+    async fn delete_create_config_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
         log::trace!("Deleting create-config of {} from database", vmid);
         let config_storage_table = self.config_storage_table();
         sqlx::query(DELETE_VMVIEWCONFIGS_BY_VMID)
             .bind(config_storage_table)
             .bind(vmid)
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBDeleting)?;
+            .map_err(VmManageError::DBDeleting)?;
 
         Ok(())
     }
 
-    async fn add_volume_db(&self, vmid: Uuid, volume: Uuid) -> VmManageResult<()> {
+    async fn add_volume_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        volume: Uuid,
+        drive_id: &str,
+        image: Option<Uuid>,
+    ) -> VmManageResult<()> {
         log::trace!("Adding volume {} of vm {} to database", volume, vmid);
         let volume_storage_table = self.volume_storage_table();
         sqlx::query(INSERT_VOLUME_BY_ID)
             .bind(volume_storage_table)
             .bind(vmid)
             .bind(volume)
-            .execute(&self.conn)
+            .bind(drive_id)
+            .bind(image)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBInsertion)?;
+            .map_err(VmManageError::DBInsertion)?;
 
         Ok(())
     }
 
-    async fn delete_volume_db(&self, vmid: Uuid, volume: Uuid) -> VmManageResult<()> {
+    async fn delete_volume_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        volume: Uuid,
+    ) -> VmManageResult<()> {
         log::trace!("Deleting volume {} of vm {} from database", volume, vmid);
         let volume_storage_table = self.volume_storage_table();
         sqlx::query(DELETE_VOLUME_BY_ID)
             .bind(volume_storage_table)
             .bind(vmid)
             .bind(volume)
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBDeleting)?;
+            .map_err(VmManageError::DBDeleting)?;
 
         Ok(())
     }
 
-    async fn get_volume_id(&self, vmid: Uuid) -> VmManageResult<Vec<Uuid>> {
-        log::trace!("Getting volume id of vm {} from database", vmid);
+    async fn get_volume_db(&self, vmid: Uuid) -> VmManageResult<Vec<PgVolumeElement>> {
+        log::trace!("Getting volumes of vm {} from database", vmid);
         let volume_storage_table = self.volume_storage_table();
-        let elements: Vec<Uuid> = sqlx::query_as::<_, PgVolumeElement>(GET_VOLUME_ALL)
+        let elements = sqlx::query_as::<_, PgVolumeElement>(GET_VOLUME_ALL)
             .bind(volume_storage_table)
             .bind(vmid)
             .fetch_all(&self.conn)
             .await
-            .map_err(|_| VmManageError::DBFetching)?
+            .map_err(VmManageError::DBFetching)?;
+
+        Ok(elements)
+    }
+
+    /// The vm a volume is attached to, if any
+    async fn get_volume_owner_db(&self, volume: Uuid) -> VmManageResult<Option<Uuid>> {
+        let volume_storage_table = self.volume_storage_table();
+        let element = sqlx::query_as::<_, PgVolumeElement>(GET_VOLUME_BY_VOLUME_ID)
+            .bind(volume_storage_table)
+            .bind(volume)
+            .fetch_optional(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+
+        Ok(element.map(|x| x.vmid))
+    }
+
+    /// The volume behind a drive of a vm
+    async fn get_drive_volume_db(&self, vmid: Uuid, drive_id: &str) -> VmManageResult<Uuid> {
+        self.get_volume_db(vmid)
+            .await?
+            .into_iter()
+            .find(|element| element.drive_id == drive_id)
+            .map(|element| element.volume_id)
+            .ok_or_else(|| VmManageError::DriveNotFound(vmid, drive_id.to_string()))
+    }
+
+    async fn get_volume_id(&self, vmid: Uuid) -> VmManageResult<Vec<Uuid>> {
+        let elements = self
+            .get_volume_db(vmid)
+            .await?
             .into_iter()
             .map(|x| x.volume_id)
             .collect();
@@ -345,139 +745,839 @@ impl VmPool {
         Ok(elements)
     }
 
-    async fn add_vm_mem_snapshot_db(
+    async fn add_network_db(
         &self,
+        conn: &mut postgres::PgConnection,
         vmid: Uuid,
-        vm_mem_snapshot_id: Uuid,
-        mem_file_path: &PathBuf,
-        snapshot_path: &PathBuf,
+        tap_id: u32,
+        guest_mac: MacAddr,
     ) -> VmManageResult<()> {
         log::trace!(
-            "Adding vm/mem snapshot {} of vm {} to database",
-            vm_mem_snapshot_id,
+            "Adding tap {} ({}) of vm {} to database",
+            tap_id,
+            guest_mac,
             vmid
         );
-        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
-        sqlx::query(INSERT_VM_MEM_SNAPSHOT_BY_ID)
-            .bind(vm_mem_snapshot_storage_table)
+        let network_storage_table = self.network_storage_table();
+        sqlx::query(INSERT_NETWORK_BY_ID)
+            .bind(network_storage_table)
             .bind(vmid)
-            .bind(vm_mem_snapshot_id)
-            .bind(mem_file_path.to_str())
-            .bind(snapshot_path.to_str())
-            .execute(&self.conn)
+            .bind(tap_id as i32)
+            .bind(guest_mac.to_string())
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBInsertion)?;
+            .map_err(VmManageError::DBInsertion)?;
 
         Ok(())
     }
 
-    async fn delete_vm_mem_snapshot_db(
+    async fn delete_network_db(
         &self,
+        conn: &mut postgres::PgConnection,
         vmid: Uuid,
-        vm_mem_snapshot_id: Uuid,
     ) -> VmManageResult<()> {
-        log::trace!(
-            "Deleting  vm/mem snapshot {} of vm {} from database",
-            vm_mem_snapshot_id,
-            vmid
-        );
-        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
-        sqlx::query(DELETE_VM_MEM_SNAPSHOT_BY_ID)
-            .bind(vm_mem_snapshot_storage_table)
+        log::trace!("Deleting taps of vm {} from database", vmid);
+        let network_storage_table = self.network_storage_table();
+        sqlx::query(DELETE_NETWORK_BY_VMID)
+            .bind(network_storage_table)
             .bind(vmid)
-            .bind(vm_mem_snapshot_id)
-            .execute(&self.conn)
+            .execute(&mut *conn)
             .await
-            .map_err(|_| VmManageError::DBDeleting)?;
+            .map_err(VmManageError::DBDeleting)?;
 
         Ok(())
     }
-}
 
-async fn init_pool(pool: VmPool) -> VmManageResult<VmPool> {
-    /* Create tables for storing */
-    /* machine core */
-    log::trace!("Initializing postgres");
-    sqlx::query(DROP_MAHCINE_CORE_TABLE_SQL)
-        .bind(pool.machine_core_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBDropTable)?;
-    sqlx::query(CREATE_MACHINE_CORE_TABLE_SQL)
-        .bind(pool.machine_core_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBCreateTable)?;
-    /* vm, mem snapshots */
-    sqlx::query(DROP_VM_MEM_SNAPSHOT_TABLE_SQL)
-        .bind(pool.vm_mem_snapshot_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBDropTable)?;
-    sqlx::query(CREATE_VM_MEM_SNAPSHOT_TABLE_SQL)
-        .bind(pool.vm_mem_snapshot_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBCreateTable)?;
-    /* vm configs */
-    sqlx::query(DROP_VMVIEWCONFIGS_TABLE_SQL)
-        .bind(pool.config_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBDropTable)?;
-    sqlx::query(CREATE_VMVIEWCONFIGS_TABLE_SQL)
-        .bind(pool.config_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBCreateTable)?;
-    /* volumes */
-    sqlx::query(DROP_VOLUME_TABLE_SQL)
-        .bind(pool.volume_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBDropTable)?;
-    sqlx::query(CREATE_VOLUME_TABLE_SQL)
-        .bind(pool.volume_storage_table())
-        .execute(&pool.conn)
-        .await
-        .map_err(|_| VmManageError::DBCreateTable)?;
+    async fn get_tap_id(&self, vmid: Uuid) -> VmManageResult<Vec<u32>> {
+        log::trace!("Getting tap id of vm {} from database", vmid);
+        let network_storage_table = self.network_storage_table();
+        let elements: Vec<u32> = sqlx::query_as::<_, PgNetworkElement>(GET_NETWORK_BY_VMID)
+            .bind(network_storage_table)
+            .bind(vmid)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .map(|x| x.tap_id as u32)
+            .collect();
 
-    /* Check storage mgr */
-    log::trace!("Checking stroage mgr");
-    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1");
-    let status = pool.storage_client.get(url).send().await?.status();
-    match status.is_success() {
-        true => log::trace!("Check storage mgr success"),
-        false => {
-            log::error!("Check storage mgr fail");
-            return Err(VmManageError::ReqwestError);
-        }
+        Ok(elements)
     }
 
-    /* Ping to network mgr */
-    log::trace!("Checking network mgr");
-    let url = format!("{}{}", pool.network_mgr_addr, "/api/v1");
-    let status = pool.network_client.get(url).send().await?.status();
-    match status.is_success() {
-        true => log::trace!("Check network mgr success"),
-        false => {
-            log::error!("Check network mgr fail");
-            return Err(VmManageError::ReqwestError);
+    /// Pick the lowest tap id neither taken by any pool nor named after a link
+    /// someone else left on the host
+    async fn allocate_tap_id(&self, conn: &mut postgres::PgConnection) -> VmManageResult<u32> {
+        log::trace!("Allocating tap id");
+        let network_storage_table = self.network_storage_table();
+        let used: Vec<u32> = sqlx::query_as::<_, PgNetworkElement>(GET_NETWORK_ALL)
+            .bind(network_storage_table)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .map(|x| x.tap_id as u32)
+            .collect();
+
+        for tap_id in (0..u32::MAX).filter(|id| !used.contains(id)) {
+            if !link_exists(&tap_name(tap_id)).await {
+                return Ok(tap_id);
+            }
+            log::debug!("{} exists on the host, skipping", tap_name(tap_id));
         }
+        Err(VmManageError::NetworkError("tap ids exhausted".into()))
     }
 
-    Ok(pool)
-}
+    /// Derive a free guest mac, or check that the `requested` one is free
+    async fn allocate_guest_mac(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        tap_id: u32,
+        requested: Option<MacAddr>,
+    ) -> VmManageResult<MacAddr> {
+        log::trace!("Allocating guest mac of tap {} of vm {}", tap_id, vmid);
+        let network_storage_table = self.network_storage_table();
+        let taken: Vec<MacAddr> = sqlx::query_as::<_, PgNetworkElement>(GET_NETWORK_ALL)
+            .bind(network_storage_table)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .filter_map(|x| x.guest_mac.parse().ok())
+            .collect();
 
-async fn get_vm_lock(pool: &mut VmPool, vmid: Uuid, lease: Option<i64>) -> VmManageResult<String> {
-    let lock_name = format!("/lock/vm/{}", vmid);
+        match requested {
+            Some(mac) if taken.contains(&mac) => Err(VmManageError::InvalidArgument(format!(
+                "guest mac {} is already taken",
+                mac
+            ))),
+            Some(mac) => Ok(mac),
+            None => Ok(allocate_mac(self.pool_id, vmid, tap_id, &taken)),
+        }
+    }
 
-    log::trace!("Getting lock for vm {}", vmid);
+    async fn add_ip_lease_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        tap_id: u32,
+        lease: &IpLease,
+    ) -> VmManageResult<()> {
+        log::trace!(
+            "Adding lease {}/{} of tap {} of vm {} to database",
+            lease.subnet,
+            lease.prefix_len,
+            tap_id,
+            vmid
+        );
+        let ip_lease_storage_table = self.ip_lease_storage_table();
+        sqlx::query(INSERT_IP_LEASE_BY_ID)
+            .bind(ip_lease_storage_table)
+            .bind(vmid)
+            .bind(tap_id as i32)
+            .bind(lease.subnet.to_string())
+            .bind(lease.prefix_len as i32)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
 
-    let resp = pool
-        .etcd_client
-        .lease_grant(lease.unwrap_or(120), None)
-        .await?;
+        Ok(())
+    }
+
+    async fn delete_ip_lease_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Releasing leases of vm {} from database", vmid);
+        let ip_lease_storage_table = self.ip_lease_storage_table();
+        sqlx::query(DELETE_IP_LEASE_BY_VMID)
+            .bind(ip_lease_storage_table)
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn get_ip_lease_db(&self, vmid: Uuid) -> VmManageResult<Vec<IpLease>> {
+        log::trace!("Getting leases of vm {} from database", vmid);
+        let ip_lease_storage_table = self.ip_lease_storage_table();
+        let elements = sqlx::query_as::<_, PgIpLeaseElement>(GET_IP_LEASE_BY_VMID)
+            .bind(ip_lease_storage_table)
+            .bind(vmid)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .filter_map(|x| {
+                Some(IpLease {
+                    subnet: x.subnet.parse().ok()?,
+                    prefix_len: x.prefix_len as u8,
+                })
+            })
+            .collect();
+
+        Ok(elements)
+    }
+
+    /// Lease the lowest /30 of the guest network not taken by any pool of the host,
+    /// or the one of the `requested` guest address
+    async fn allocate_ip_lease(
+        &self,
+        conn: &mut postgres::PgConnection,
+        requested: Option<Ipv4Addr>,
+    ) -> VmManageResult<IpLease> {
+        log::trace!("Allocating ip lease");
+        let ip_lease_storage_table = self.ip_lease_storage_table();
+        let leased: Vec<_> = sqlx::query_as::<_, PgIpLeaseElement>(GET_IP_LEASE_ALL)
+            .bind(ip_lease_storage_table)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .filter_map(|x| x.subnet.parse().ok())
+            .collect();
+
+        match requested {
+            Some(guest_ip) => lease_of_guest_ip(&self.guest_network, guest_ip, &leased),
+            None => allocate_lease(&self.guest_network, &leased),
+        }
+    }
+
+    async fn add_port_forward_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        forward: &PortForward,
+    ) -> VmManageResult<()> {
+        log::trace!(
+            "Adding port forward {}/{} of vm {} to database",
+            forward.host_port,
+            forward.protocol.as_str(),
+            vmid
+        );
+        sqlx::query(INSERT_PORT_FORWARD)
+            .bind(self.port_forward_storage_table())
+            .bind(vmid)
+            .bind(forward.protocol.as_str())
+            .bind(forward.host_port as i32)
+            .bind(forward.guest_ip.to_string())
+            .bind(forward.guest_port as i32)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn delete_port_forward_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Deleting port forwards of vm {} from database", vmid);
+        sqlx::query(DELETE_PORT_FORWARD_BY_VMID)
+            .bind(self.port_forward_storage_table())
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn get_port_forward_db(&self, vmid: Uuid) -> VmManageResult<Vec<PortForward>> {
+        log::trace!("Getting port forwards of vm {} from database", vmid);
+        let elements = sqlx::query_as::<_, PgPortForwardElement>(GET_PORT_FORWARD_BY_VMID)
+            .bind(self.port_forward_storage_table())
+            .bind(vmid)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .filter_map(|x| {
+                Some(PortForward {
+                    protocol: x.protocol.parse().ok()?,
+                    host_port: x.host_port as u16,
+                    guest_ip: x.guest_ip.parse().ok()?,
+                    guest_port: x.guest_port as u16,
+                })
+            })
+            .collect();
+
+        Ok(elements)
+    }
+
+    /// Pick the lowest host port of the range neither forwarded for `protocol`
+    /// by any pool nor bound by another process on the host
+    async fn allocate_host_port(
+        &self,
+        conn: &mut postgres::PgConnection,
+        protocol: ForwardProtocol,
+    ) -> VmManageResult<u16> {
+        log::trace!("Allocating host port");
+        let mut taken: Vec<u16> =
+            sqlx::query_as::<_, PgPortForwardElement>(GET_PORT_FORWARD_BY_PROTOCOL)
+                .bind(self.port_forward_storage_table())
+                .bind(protocol.as_str())
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(VmManageError::DBFetching)?
+                .into_iter()
+                .map(|x| x.host_port as u16)
+                .collect();
+
+        loop {
+            let port = allocate_port(&self.port_forward_range, &taken)?;
+            if host_port_free(protocol, port) {
+                return Ok(port);
+            }
+            log::debug!(
+                "Host port {}/{} is in use, skipping",
+                port,
+                protocol.as_str()
+            );
+            taken.push(port);
+        }
+    }
+
+    async fn add_disk_snapshot_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+        volume_snapshot: &VolumeSnapshot,
+    ) -> VmManageResult<()> {
+        log::trace!(
+            "Adding snapshot {} of volume {} to disk snapshot {} of vm {}",
+            volume_snapshot.volume_snapshot_id,
+            volume_snapshot.volume_id,
+            snapshot_id,
+            vmid
+        );
+        sqlx::query(INSERT_DISK_SNAPSHOT)
+            .bind(self.disk_snapshot_storage_table())
+            .bind(vmid)
+            .bind(snapshot_id)
+            .bind(volume_snapshot.volume_id)
+            .bind(volume_snapshot.volume_snapshot_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn delete_disk_snapshot_by_vmid_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Deleting disk snapshots of vm {} from database", vmid);
+        sqlx::query(DELETE_DISK_SNAPSHOT_BY_VMID)
+            .bind(self.disk_snapshot_storage_table())
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn get_disk_snapshot_db(
+        &self,
+        vmid: Uuid,
+        snapshot_id: Uuid,
+    ) -> VmManageResult<DiskSnapshotInfo> {
+        log::trace!(
+            "Getting disk snapshot {} of vm {} from database",
+            snapshot_id,
+            vmid
+        );
+        let elements = sqlx::query_as::<_, PgDiskSnapshotElement>(GET_DISK_SNAPSHOT_BY_ID)
+            .bind(self.disk_snapshot_storage_table())
+            .bind(vmid)
+            .bind(snapshot_id)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+
+        disk_snapshot_infos(elements)
+            .pop()
+            .ok_or(VmManageError::DiskSnapshotNotFound(snapshot_id))
+    }
+
+    async fn get_disk_snapshot_all_db(&self, vmid: Uuid) -> VmManageResult<Vec<DiskSnapshotInfo>> {
+        log::trace!("Getting all disk snapshots of vm {} from database", vmid);
+        let elements = sqlx::query_as::<_, PgDiskSnapshotElement>(GET_DISK_SNAPSHOT_ALL)
+            .bind(self.disk_snapshot_storage_table())
+            .bind(vmid)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+
+        Ok(disk_snapshot_infos(elements))
+    }
+
+    async fn add_netns_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+        transit_index: u32,
+    ) -> VmManageResult<()> {
+        log::trace!("Adding network namespace of vm {} to database", vmid);
+        sqlx::query(INSERT_NETNS)
+            .bind(self.netns_storage_table())
+            .bind(vmid)
+            .bind(transit_index as i32)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn delete_netns_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Deleting network namespace of vm {} from database", vmid);
+        sqlx::query(DELETE_NETNS_BY_VMID)
+            .bind(self.netns_storage_table())
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    /// Pick the lowest transit index not taken by any pool whose veth is not
+    /// left on the host by someone else
+    async fn allocate_transit_index(
+        &self,
+        conn: &mut postgres::PgConnection,
+    ) -> VmManageResult<u32> {
+        log::trace!("Allocating transit index");
+        let used: Vec<u32> = sqlx::query_as::<_, PgNetnsElement>(GET_NETNS_ALL)
+            .bind(self.netns_storage_table())
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .map(|x| x.transit_index as u32)
+            .collect();
+
+        for index in (0..u32::MAX).filter(|index| !used.contains(index)) {
+            /* The transit network bounds the indexes */
+            let netns = netns(self, Uuid::nil(), index)?;
+            if !link_exists(&netns.veth_host).await {
+                return Ok(index);
+            }
+            log::debug!("{} exists on the host, skipping", netns.veth_host);
+        }
+        Err(VmManageError::NetworkError(
+            "transit indexes exhausted".into(),
+        ))
+    }
+
+    /// Transit index of the namespace of a vm, `None` if it runs in the host namespace
+    async fn get_netns_db(&self, vmid: Uuid) -> VmManageResult<Option<u32>> {
+        let element = sqlx::query_as::<_, PgNetnsElement>(GET_NETNS_BY_VMID)
+            .bind(self.netns_storage_table())
+            .bind(vmid)
+            .fetch_optional(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+
+        Ok(element.map(|x| x.transit_index as u32))
+    }
+
+    async fn add_security_group_db(
+        &self,
+        id: Uuid,
+        name: &String,
+        rules: &Vec<SecurityGroupRule>,
+    ) -> VmManageResult<()> {
+        log::trace!("Adding security group {} to database", id);
+        sqlx::query(INSERT_SECURITY_GROUP)
+            .bind(self.security_group_storage_table())
+            .bind(id)
+            .bind(name)
+            .bind(sqlx::types::Json(rules))
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn delete_security_group_db(&self, id: Uuid) -> VmManageResult<()> {
+        log::trace!("Deleting security group {} from database", id);
+        sqlx::query(DELETE_SECURITY_GROUP_BY_ID)
+            .bind(self.security_group_storage_table())
+            .bind(id)
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn get_security_group_db(&self, id: Uuid) -> VmManageResult<SecurityGroup> {
+        log::trace!("Getting security group {} from database", id);
+        let element = sqlx::query_as::<_, PgSecurityGroupElement>(GET_SECURITY_GROUP_BY_ID)
+            .bind(self.security_group_storage_table())
+            .bind(id)
+            .fetch_one(&self.conn)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => VmManageError::SecurityGroupNotFound(id),
+                e => VmManageError::DBFetching(e),
+            })?;
+
+        Ok(element.into())
+    }
+
+    async fn get_security_group_all_db(&self) -> VmManageResult<Vec<SecurityGroup>> {
+        log::trace!("Getting all security groups from database");
+        let elements = sqlx::query_as::<_, PgSecurityGroupElement>(GET_SECURITY_GROUP_ALL)
+            .bind(self.security_group_storage_table())
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?
+            .into_iter()
+            .map(SecurityGroup::from)
+            .collect();
+
+        Ok(elements)
+    }
+
+    async fn add_security_group_binding_db(
+        &self,
+        vmid: Uuid,
+        group_id: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Attaching security group {} to vm {}", group_id, vmid);
+        sqlx::query(INSERT_SECURITY_GROUP_BINDING)
+            .bind(self.security_group_binding_storage_table())
+            .bind(vmid)
+            .bind(group_id)
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn delete_security_group_binding_db(
+        &self,
+        vmid: Uuid,
+        group_id: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Detaching security group {} from vm {}", group_id, vmid);
+        sqlx::query(DELETE_SECURITY_GROUP_BINDING)
+            .bind(self.security_group_binding_storage_table())
+            .bind(vmid)
+            .bind(group_id)
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn delete_security_group_binding_by_vmid_db(
+        &self,
+        conn: &mut postgres::PgConnection,
+        vmid: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!("Detaching all security groups from vm {}", vmid);
+        sqlx::query(DELETE_SECURITY_GROUP_BINDING_BY_VMID)
+            .bind(self.security_group_binding_storage_table())
+            .bind(vmid)
+            .execute(&mut *conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+
+    async fn get_security_group_id_by_vmid_db(&self, vmid: Uuid) -> VmManageResult<Vec<Uuid>> {
+        log::trace!("Getting security groups of vm {} from database", vmid);
+        let elements =
+            sqlx::query_as::<_, PgSecurityGroupBindingElement>(GET_SECURITY_GROUP_BINDING_BY_VMID)
+                .bind(self.security_group_binding_storage_table())
+                .bind(vmid)
+                .fetch_all(&self.conn)
+                .await
+                .map_err(VmManageError::DBFetching)?
+                .into_iter()
+                .map(|x| x.group_id)
+                .collect();
+
+        Ok(elements)
+    }
+
+    async fn get_vmid_by_security_group_db(&self, group_id: Uuid) -> VmManageResult<Vec<Uuid>> {
+        log::trace!("Getting vms of security group {} from database", group_id);
+        let elements =
+            sqlx::query_as::<_, PgSecurityGroupBindingElement>(GET_SECURITY_GROUP_BINDING_BY_GROUP)
+                .bind(self.security_group_binding_storage_table())
+                .bind(group_id)
+                .fetch_all(&self.conn)
+                .await
+                .map_err(VmManageError::DBFetching)?
+                .into_iter()
+                .map(|x| x.vmid)
+                .collect();
+
+        Ok(elements)
+    }
+
+    async fn add_vm_mem_snapshot_db(
+        &self,
+        vmid: Uuid,
+        vm_mem_snapshot_id: Uuid,
+        mem_file_path: &PathBuf,
+        snapshot_path: &PathBuf,
+        status: MachineState,
+    ) -> VmManageResult<()> {
+        log::trace!(
+            "Adding vm/mem snapshot {} of vm {} to database",
+            vm_mem_snapshot_id,
+            vmid
+        );
+        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
+        sqlx::query(INSERT_VM_MEM_SNAPSHOT_BY_ID)
+            .bind(vm_mem_snapshot_storage_table)
+            .bind(vmid)
+            .bind(vm_mem_snapshot_id)
+            .bind(mem_file_path.to_str())
+            .bind(snapshot_path.to_str())
+            .bind(status)
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBInsertion)?;
+
+        Ok(())
+    }
+
+    async fn get_vm_mem_snapshot_db(
+        &self,
+        vmid: Uuid,
+        vm_mem_snapshot_id: Uuid,
+    ) -> VmManageResult<PgVmMemSnapshotElement> {
+        log::trace!(
+            "Getting vm/mem snapshot {} of vm {} from database",
+            vm_mem_snapshot_id,
+            vmid
+        );
+        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
+        let element = sqlx::query_as::<_, PgVmMemSnapshotElement>(GET_VM_MEM_SNAPSHOT_BY_ID)
+            .bind(vm_mem_snapshot_storage_table)
+            .bind(vmid)
+            .bind(vm_mem_snapshot_id)
+            .fetch_one(&self.conn)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => {
+                    VmManageError::VmMemSnapshotNotFound(vm_mem_snapshot_id)
+                }
+                e => VmManageError::DBFetching(e),
+            })?;
+
+        Ok(element)
+    }
+
+    async fn get_vm_mem_snapshot_all_db(
+        &self,
+        vmid: Uuid,
+    ) -> VmManageResult<Vec<PgVmMemSnapshotElement>> {
+        log::trace!("Getting vm/mem snapshots of vm {} from database", vmid);
+        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
+        let elements = sqlx::query_as::<_, PgVmMemSnapshotElement>(GET_VM_MEM_SNAPSHOT_ALL)
+            .bind(vm_mem_snapshot_storage_table)
+            .bind(vmid)
+            .fetch_all(&self.conn)
+            .await
+            .map_err(VmManageError::DBFetching)?;
+
+        Ok(elements)
+    }
+
+    async fn delete_vm_mem_snapshot_db(
+        &self,
+        vmid: Uuid,
+        vm_mem_snapshot_id: Uuid,
+    ) -> VmManageResult<()> {
+        log::trace!(
+            "Deleting  vm/mem snapshot {} of vm {} from database",
+            vm_mem_snapshot_id,
+            vmid
+        );
+        let vm_mem_snapshot_storage_table = self.vm_mem_snapshot_storage_table();
+        sqlx::query(DELETE_VM_MEM_SNAPSHOT_BY_ID)
+            .bind(vm_mem_snapshot_storage_table)
+            .bind(vmid)
+            .bind(vm_mem_snapshot_id)
+            .execute(&self.conn)
+            .await
+            .map_err(VmManageError::DBDeleting)?;
+
+        Ok(())
+    }
+}
+
+async fn init_pool(pool: VmPool) -> VmManageResult<VmPool> {
+    /* Create tables for storing, keep the ones left by a previous run */
+    /* machine core */
+    log::trace!("Initializing postgres");
+    sqlx::query(CREATE_MACHINE_CORE_TABLE_SQL)
+        .bind(pool.machine_core_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* vm, mem snapshots */
+    sqlx::query(CREATE_VM_MEM_SNAPSHOT_TABLE_SQL)
+        .bind(pool.vm_mem_snapshot_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* vm configs */
+    sqlx::query(CREATE_VMVIEWCONFIGS_TABLE_SQL)
+        .bind(pool.config_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* volumes */
+    sqlx::query(CREATE_VOLUME_TABLE_SQL)
+        .bind(pool.volume_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* networks */
+    sqlx::query(CREATE_NETWORK_TABLE_SQL)
+        .bind(pool.network_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* bring tables of older releases up to date */
+    sqlx::query(MIGRATE_VM_MEM_SNAPSHOT_TABLE_SQL)
+        .bind(pool.vm_mem_snapshot_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(BACKFILL_VM_MEM_SNAPSHOT_STATUS_SQL)
+        .bind(pool.vm_mem_snapshot_storage_table())
+        .bind(PAUSED)
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(MIGRATE_VMVIEWCONFIGS_TABLE_SQL)
+        .bind(pool.config_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(MIGRATE_VOLUME_TABLE_SQL)
+        .bind(pool.volume_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(BACKFILL_VOLUME_DRIVE_ID_SQL)
+        .bind(pool.volume_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(MIGRATE_VOLUME_ID_UNIQUE_SQL)
+        .bind(pool.volume_storage_table())
+        .bind(format!("{}_volume_id_key", pool.volume_storage_table()))
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* ip leases */
+    sqlx::query(CREATE_IP_LEASE_TABLE_SQL)
+        .bind(pool.ip_lease_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* disk snapshots */
+    sqlx::query(CREATE_DISK_SNAPSHOT_TABLE_SQL)
+        .bind(pool.disk_snapshot_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* network namespaces */
+    sqlx::query(CREATE_NETNS_TABLE_SQL)
+        .bind(pool.netns_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* port forwards */
+    sqlx::query(CREATE_PORT_FORWARD_TABLE_SQL)
+        .bind(pool.port_forward_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    /* security groups */
+    sqlx::query(CREATE_SECURITY_GROUP_TABLE_SQL)
+        .bind(pool.security_group_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+    sqlx::query(CREATE_SECURITY_GROUP_BINDING_TABLE_SQL)
+        .bind(pool.security_group_binding_storage_table())
+        .execute(&pool.conn)
+        .await
+        .map_err(VmManageError::DBCreateTable)?;
+
+    /* Check storage mgr */
+    log::trace!("Checking stroage mgr");
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1");
+    let status = pool.storage_client.get(url).send().await?.status();
+    match status.is_success() {
+        true => log::trace!("Check storage mgr success"),
+        false => {
+            log::error!("Check storage mgr fail");
+            return Err(VmManageError::UpstreamStatus(
+                "storage mgr".to_string(),
+                status,
+                None,
+            ));
+        }
+    }
+
+    /* Ping to network mgr */
+    log::trace!("Checking network mgr");
+    let url = format!("{}{}", pool.network_mgr_addr, "/api/v1");
+    let status = pool.network_client.get(url).send().await?.status();
+    match status.is_success() {
+        true => log::trace!("Check network mgr success"),
+        false => {
+            log::error!("Check network mgr fail");
+            return Err(VmManageError::UpstreamStatus(
+                "network mgr".to_string(),
+                status,
+                None,
+            ));
+        }
+    }
+
+    Ok(pool)
+}
+
+async fn get_vm_lock(pool: &mut VmPool, vmid: Uuid, lease: Option<i64>) -> VmManageResult<String> {
+    let lock_name = format!("/lock/vm/{}", vmid);
+
+    log::trace!("Getting lock for vm {}", vmid);
+
+    let resp = pool
+        .etcd_client
+        .lease_grant(lease.unwrap_or(120), None)
+        .await?;
     let lease_id = resp.id();
     let lock_options = etcd_client::LockOptions::new().with_lease(lease_id);
 
@@ -504,16 +1604,33 @@ async fn get_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<Machine> {
     let core = pool.get_core_db(vmid).await?;
 
     /* Rebuild machine agent */
-    let machine = Machine::rebuild(core).map_err(|_| VmManageError::MachineRebuild)?;
+    let machine = Machine::rebuild(core).map_err(VmManageError::MachineRebuild)?;
     Ok(machine)
 }
 
-pub async fn create_vm(
+pub async fn create_vm(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    create_config: &MachineCreateConfig,
+) -> VmManageResult<Uuid> {
+    log::trace!("Creating vm {}", vmid);
+    let mut rollback = Rollback::default();
+    match do_create_vm(pool, vmid, create_config, &mut rollback).await {
+        Ok(vmid) => Ok(vmid),
+        Err(e) => {
+            log::error!("Fail to create vm {}: {}, rolling back", vmid, e.report());
+            rollback.run(pool).await;
+            Err(e)
+        }
+    }
+}
+
+async fn do_create_vm(
     pool: &mut VmPool,
     vmid: Uuid,
     create_config: &MachineCreateConfig,
+    rollback: &mut Rollback,
 ) -> VmManageResult<Uuid> {
-    log::trace!("Creating vm {}", vmid);
     /* Read critical parameters */
     let socket_path = pool.socket_path(vmid);
     let log_fifo = pool.log_fifo(vmid);
@@ -540,23 +1657,53 @@ pub async fn create_vm(
     let kernel_image_path =
         get_kernel_image_path(&create_config.kernel_name, &create_config.kernel_version)?;
 
-    /* Request for a volume from storage manager */
-    let volume_id = create_volume(pool, create_config.volume_size_in_mib, None).await?;
+    /* Request for a volume from storage manager, a child of the image if any */
+    let size = root_volume_size(pool, create_config).await?;
+    let volume_id = create_volume(pool, size, None, create_config.image_id).await?;
+    rollback.push(Undo::DeleteVolume(volume_id));
     let volume_path = attach_volume(pool, volume_id).await?;
+    rollback.push(Undo::DetachVolume(volume_id));
 
     /* Config the root device using the volume */
     let root_device = Drive {
-        drive_id: "rootfs".to_string(),
+        drive_id: ROOT_DRIVE_ID.to_string(),
         partuuid: Some(volume_id.to_string()),
         is_root_device: true,
         cache_type: None,
         is_read_only: false,
         path_on_host: PathBuf::from(volume_path),
-        rate_limiter: None,
+        rate_limiter: create_config.root_drive_rate_limiter.to_owned(),
         io_engine: None,
         socket: None,
     };
 
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+
+    /* Request a tap with its own subnet per NIC from network manager */
+    let nics = nic_configs(create_config, 1);
+    let netns = match create_config.enable_netns == Some(true) && !nics.is_empty() {
+        true => Some(add_netns(pool, &mut tx, vmid, rollback).await?),
+        false => None,
+    };
+    let mut network_interfaces = Vec::new();
+    let mut nic_metadata = Vec::new();
+    let mut kernel_args = DEFAULT_KERNEL_ARGS.to_string();
+    for (index, nic) in nics.iter().enumerate() {
+        let (network_interface, lease) =
+            add_network_interface(pool, &mut tx, vmid, nic, netns.as_ref(), None, rollback).await?;
+        let iface = format!("eth{}", index);
+        if index == 0 {
+            kernel_args = format!("{} {}", kernel_args, lease.kernel_ip_arg(&iface));
+        }
+        nic_metadata.push(lease.metadata(&iface, network_interface.guest_mac.as_deref()));
+        network_interfaces.push(network_interface);
+    }
+    let init_metadata = with_nic_metadata(&create_config.initial_metadata, nic_metadata)?;
+
     /* Build the config */
     let config = Config {
         socket_path: Some(socket_path),
@@ -569,22 +1716,22 @@ pub async fn create_vm(
         metrics_clear: Some(false), // Keep metrics fifo
         kernel_image_path: Some(kernel_image_path),
         initrd_path: None,
-        kernel_args: None,
+        kernel_args: Some(kernel_args),
         drives: Some(vec![root_device]), // Root device
-        network_interfaces: None,        // TODO: network interface
+        network_interfaces: Some(network_interfaces),
         vsock_devices: None,
         machine_cfg: Some(machine_cfg),
         disable_validation: true, // Enable validation
         enable_jailer: false,     // Disable jailer
         jailer_cfg: None,
         vmid: None,
-        net_ns: None,
+        net_ns: netns.as_ref().map(netns_path),
         network_clear: Some(true),
         forward_signals: None,
         seccomp_level: None,
         mmds_address: None,
         balloon: None,
-        init_metadata: create_config.initial_metadata.to_owned(), // Initial metadata
+        init_metadata, // Initial metadata and the leases of the NICs
         stderr: None,
         stdin: None,
         stdout: None,
@@ -594,131 +1741,1081 @@ pub async fn create_vm(
 
     /* Assemble database metadata */
     /* Create the machine */
-    let machine = Machine::new(config.to_owned()).map_err(|_| VmManageError::MachineCreate)?;
+    let machine = Machine::new(config.to_owned()).map_err(VmManageError::MachineCreate)?;
 
-    /* Dump to machine core */
-    let core = machine
+    /* Dump to machine core, without the namespace rustcracker made up */
+    let mut core = machine
         .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
+        .map_err(VmManageError::MachineDumpCore)?;
+    core.cfg.net_ns = netns.as_ref().map(netns_path);
 
     /* Add the creating config to database */
-    pool.add_create_config_db(vmid, create_config).await?;
+    pool.add_create_config_db(&mut tx, vmid, create_config)
+        .await?;
 
     /* Add core to database */
-    pool.add_core_db(vmid, &core, CREATED).await?;
+    pool.add_core_db(&mut tx, vmid, &core, CREATED).await?;
 
     /* Add volume to database */
-    pool.add_volume_db(vmid, volume_id).await?;
+    pool.add_volume_db(
+        &mut tx,
+        vmid,
+        volume_id,
+        ROOT_DRIVE_ID,
+        create_config.image_id,
+    )
+    .await?;
+
+    tx.commit().await.map_err(VmManageError::DBTransaction)?;
 
     log::trace!("Created vm {}", vmid);
     Ok(vmid)
 }
 
+/// NICs asked for by a create config, `count` default ones if it names none
+fn nic_configs(create_config: &MachineCreateConfig, count: usize) -> Vec<NetworkInterfaceConfig> {
+    let nics = match &create_config.network_interfaces {
+        Some(nics) => nics.to_owned(),
+        None => vec![NetworkInterfaceConfig::default(); count],
+    };
+    nics.into_iter()
+        .map(|nic| NetworkInterfaceConfig {
+            rx_rate_limiter: nic
+                .rx_rate_limiter
+                .or(create_config.network_rx_rate_limiter.to_owned()),
+            tx_rate_limiter: nic
+                .tx_rate_limiter
+                .or(create_config.network_tx_rate_limiter.to_owned()),
+            ..nic
+        })
+        .collect()
+}
+
+/// Publish the leases of all NICs under `network.interfaces` of the initial
+/// metadata, which has to be a JSON object leaving that key to us
+fn with_nic_metadata(
+    initial_metadata: &Option<String>,
+    nics: Vec<serde_json::Value>,
+) -> VmManageResult<Option<String>> {
+    if nics.is_empty() {
+        return Ok(initial_metadata.to_owned());
+    }
+    let mut metadata = match initial_metadata {
+        Some(metadata) => serde_json::from_str::<serde_json::Value>(metadata).map_err(|e| {
+            VmManageError::InvalidArgument(format!("invalid initial metadata: {}", e))
+        })?,
+        None => serde_json::json!({}),
+    };
+    let Some(object) = metadata.as_object_mut() else {
+        return Err(VmManageError::InvalidArgument(
+            "initial metadata must be a JSON object".to_string(),
+        ));
+    };
+    if object.contains_key("network") {
+        return Err(VmManageError::InvalidArgument(
+            "initial metadata must not set `network`, it carries the NIC leases".to_string(),
+        ));
+    }
+    object.insert(
+        "network".to_string(),
+        serde_json::json!({ "interfaces": nics }),
+    );
+    Ok(Some(metadata.to_string()))
+}
+
+/// Create the network namespace of a vm with its veth to the host bridge
+async fn add_netns(
+    pool: &mut VmPool,
+    conn: &mut postgres::PgConnection,
+    vmid: Uuid,
+    rollback: &mut Rollback,
+) -> VmManageResult<Netns> {
+    let index = pool.allocate_transit_index(&mut *conn).await?;
+    let netns = netns(pool, vmid, index)?;
+    create_netns(&netns).await?;
+    rollback.push(Undo::DeleteNetns(netns.clone()));
+    pool.add_netns_db(conn, vmid, index).await?;
+    Ok(netns)
+}
+
+fn netns_path(netns: &Netns) -> PathBuf {
+    PathBuf::from("/var/run/netns").join(&netns.name)
+}
+
+/// The namespace of a vm as created by `add_netns`, `None` if it runs in the
+/// host namespace. `Config.net_ns` cannot tell, rustcracker always fills it in.
+async fn vm_netns(pool: &VmPool, vmid: Uuid) -> VmManageResult<Option<Netns>> {
+    match pool.get_netns_db(vmid).await? {
+        Some(index) => Ok(Some(netns(pool, vmid, index)?)),
+        None => Ok(None),
+    }
+}
+
+/// Firecracker is launched by rustcracker in the namespace of the manager,
+/// a vm with a namespace of its own is launched through `ip netns exec`
+fn set_netns_command(machine: &mut Machine, netns: Option<&Netns>) {
+    let config = machine.get_config();
+    let (Some(netns), Some(socket_path), Some(id)) = (netns, config.socket_path, config.vmid)
+    else {
+        return;
+    };
+    machine.set_command(firecracker_command(&socket_path, &id, Some(netns)));
+}
+
+/// Bare firecracker process serving its API on `socket_path`
+fn firecracker_command(
+    socket_path: &PathBuf,
+    id: &str,
+    netns: Option<&Netns>,
+) -> tokio::process::Command {
+    let firecracker =
+        env::var("FIRECRACKER_BINARY_PATH").unwrap_or_else(|_| "firecracker".to_string());
+    let mut command = match netns {
+        Some(netns) => {
+            let mut command = tokio::process::Command::new("ip");
+            command.args(["netns", "exec", &netns.name, &firecracker]);
+            command
+        }
+        None => tokio::process::Command::new(&firecracker),
+    };
+    command
+        .arg("--api-sock")
+        .arg(socket_path)
+        .args(["--seccomp-level", "0", "--id", id]);
+    command
+}
+
+/// Launch firecracker for `config` without configuring anything, ready for a
+/// snapshot to be loaded. Returns the pid once the API socket is up.
+async fn spawn_vmm(config: &Config, netns: Option<&Netns>) -> VmManageResult<u32> {
+    let (Some(socket_path), Some(id)) = (config.socket_path.as_ref(), config.vmid.as_ref()) else {
+        return Err(VmManageError::MachineStart(MachineError::ArgWrong(
+            "socket path and vmid are required to launch firecracker".to_string(),
+        )));
+    };
+
+    /* A socket left by a previous process would fail the bind */
+    let _ = tokio::fs::remove_file(socket_path).await;
+    let mut child = firecracker_command(socket_path, id, netns)
+        .spawn()
+        .map_err(|e| VmManageError::MachineStart(MachineError::Execute(e.to_string())))?;
+    let pid = child.id().ok_or_else(|| {
+        VmManageError::MachineStart(MachineError::Execute("firecracker exited".to_string()))
+    })?;
+    /* Reap the process whenever it exits */
+    tokio::spawn(async move {
+        let _ = child.wait().await;
+    });
+
+    let init_timeout = config
+        .agent_init_timeout
+        .unwrap_or(DEFAULT_FIRECRACKER_INIT_TIMEOUT_SECONDS);
+    let socket_up = tokio::time::timeout(Duration::from_secs_f64(init_timeout), async {
+        while tokio::fs::metadata(socket_path).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    if socket_up.is_err() {
+        let _ = kill_vmm(pid).await;
+        return Err(VmManageError::MachineStart(MachineError::Initialize(
+            format!("no firecracker socket after {} seconds", init_timeout),
+        )));
+    }
+
+    Ok(pid)
+}
+
+/// Whether the recorded pid is still the firecracker serving the API socket of
+/// `core`, and not a process that took the pid over since
+async fn is_own_vmm(core: &MachineCore) -> bool {
+    if core.pid == 0 {
+        return false;
+    }
+    let Ok(cmdline) = tokio::fs::read(format!("/proc/{}/cmdline", core.pid)).await else {
+        return false;
+    };
+    let socket_path = core.socket_path.to_string_lossy();
+    String::from_utf8_lossy(&cmdline)
+        .split('\0')
+        .any(|arg| arg == socket_path)
+}
+
+/// Terminate a firecracker process by pid and wait for it to exit. Machines
+/// rebuilt from a core hold no child process, `stop_vmm` does not signal them.
+async fn kill_vmm(pid: u32) -> VmManageResult<()> {
+    if pid == 0 {
+        return Ok(());
+    }
+    let pid = pid as libc::pid_t;
+    let alive = || unsafe { libc::kill(pid, 0) } == 0;
+
+    /* Ask nicely first, then force */
+    for signal in [libc::SIGTERM, libc::SIGKILL] {
+        if !alive() {
+            return Ok(());
+        }
+        unsafe { libc::kill(pid, signal) };
+        for _ in 0..VMM_EXIT_POLLS {
+            if !alive() {
+                return Ok(());
+            }
+            tokio::time::sleep(VMM_EXIT_POLL_INTERVAL).await;
+        }
+    }
+    Err(VmManageError::MachineStop(MachineError::Execute(format!(
+        "firecracker process {} did not exit",
+        pid
+    ))))
+}
+
+/// Provision a tap for `nic` with its lease, mac and a closed firewall. In
+/// `netns` the tap goes by `host_dev_name` if given, by its own name otherwise.
+async fn add_network_interface(
+    pool: &mut VmPool,
+    conn: &mut postgres::PgConnection,
+    vmid: Uuid,
+    nic: &NetworkInterfaceConfig,
+    netns: Option<&Netns>,
+    host_dev_name: Option<&str>,
+    rollback: &mut Rollback,
+) -> VmManageResult<(NetworkInterface, IpLease)> {
+    let requested_mac = nic
+        .guest_mac
+        .as_ref()
+        .map(|mac| mac.parse::<MacAddr>())
+        .transpose()?;
+    let tap_id = pool.allocate_tap_id(&mut *conn).await?;
+    let lease = pool.allocate_ip_lease(&mut *conn, nic.guest_ip).await?;
+    let guest_mac = pool
+        .allocate_guest_mac(&mut *conn, vmid, tap_id, requested_mac)
+        .await?;
+    let host_dev_name = host_dev_name
+        .map(str::to_string)
+        .unwrap_or_else(|| tap_name(tap_id));
+    let netns_tap = netns.map(|netns| (netns, host_dev_name.as_str()));
+    let network_interface =
+        create_network_interface(pool, vmid, tap_id, nic, &lease, guest_mac, netns_tap).await?;
+    rollback.push(Undo::DeleteTap(vmid, tap_id));
+    pool.add_network_db(&mut *conn, vmid, tap_id, guest_mac)
+        .await?;
+    pool.add_ip_lease_db(&mut *conn, vmid, tap_id, &lease)
+        .await?;
+
+    /* No security group yet, only replies may come in */
+    let host_dev_name = network_interface
+        .host_dev_name
+        .to_string_lossy()
+        .to_string();
+    let netns = netns.map(|netns| netns.name.to_owned());
+    firewall::apply(netns.as_deref(), &host_dev_name, &[]).await?;
+    rollback.push(Undo::RemoveFirewall(netns, host_dev_name));
+
+    Ok((network_interface, lease))
+}
+
+/// Host devices of the network interfaces of a machine config
+fn host_dev_names(config: &Config) -> Vec<String> {
+    config
+        .network_interfaces
+        .iter()
+        .flatten()
+        .map(|network_interface| {
+            network_interface
+                .host_dev_name
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Size of the root volume to create: the one asked for, checked against the
+/// image if any, or else the size of the image
+async fn root_volume_size(
+    pool: &mut VmPool,
+    create_config: &MachineCreateConfig,
+) -> VmManageResult<i32> {
+    if let Some(size) = create_config.volume_size_in_mib.filter(|size| *size <= 0) {
+        return Err(VmManageError::InvalidArgument(format!(
+            "invalid volume size {} MiB",
+            size
+        )));
+    }
+    let image = match create_config.image_id {
+        Some(image) => image,
+        None => {
+            return create_config.volume_size_in_mib.ok_or_else(|| {
+                VmManageError::InvalidArgument("volume_size_in_mib is required".to_string())
+            })
+        }
+    };
+    let image_size = get_image_size(pool, image).await?;
+    match create_config.volume_size_in_mib {
+        Some(size) if size < image_size => Err(VmManageError::InvalidArgument(format!(
+            "volume of {} MiB is smaller than image {} of {} MiB",
+            size, image, image_size
+        ))),
+        Some(size) => Ok(size),
+        None => Ok(image_size),
+    }
+}
+
+pub async fn create_vm_from_vm_mem_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    create_config: &MachineCreateConfig,
+    source: &VmSnapshotSource,
+) -> VmManageResult<Uuid> {
+    log::trace!(
+        "Creating vm {} from vm/mem snapshot {} of {}",
+        vmid,
+        source.snapshot_id,
+        source.vmid
+    );
+    let mut rollback = Rollback::default();
+    match do_create_vm_from_vm_mem_snapshot(pool, vmid, create_config, source, &mut rollback).await
+    {
+        Ok(vmid) => Ok(vmid),
+        Err(e) => {
+            log::error!("Fail to create vm {}: {}, rolling back", vmid, e.report());
+            rollback.run(pool).await;
+            Err(e)
+        }
+    }
+}
+
+async fn do_create_vm_from_vm_mem_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    create_config: &MachineCreateConfig,
+    source: &VmSnapshotSource,
+    rollback: &mut Rollback,
+) -> VmManageResult<Uuid> {
+    let snapshot = pool
+        .get_vm_mem_snapshot_db(source.vmid, source.snapshot_id)
+        .await?;
+
+    /* Start from the boot config of the source machine */
+    let mut config = pool.get_core_db(source.vmid).await?.cfg;
+    config.socket_path = Some(pool.socket_path(vmid));
+    config.log_fifo = Some(pool.log_fifo(vmid));
+    config.metrics_fifo = Some(pool.metrics_fifo(vmid));
+    config.vmid = None;
+    config.net_ns = None;
+
+    /* Only the root drive can be given a volume of its own */
+    if config
+        .drives
+        .iter()
+        .flatten()
+        .any(|drive| !drive.is_root_device)
+    {
+        return Err(VmManageError::InvalidArgument(
+            "vms with data drives cannot be cloned from a snapshot".to_string(),
+        ));
+    }
+    if create_config.image_id.is_some() {
+        return Err(VmManageError::InvalidArgument(
+            "a vm cloned from a snapshot keeps the image of its source".to_string(),
+        ));
+    }
+
+    /* Request a child of the source root volume from storage manager, no
+    smaller than its parent */
+    let root = pool
+        .get_volume_db(source.vmid)
+        .await?
+        .into_iter()
+        .find(|element| element.drive_id == ROOT_DRIVE_ID)
+        .ok_or_else(|| VmManageError::DriveNotFound(source.vmid, ROOT_DRIVE_ID.to_string()))?;
+    let (parent, image) = (root.volume_id, root.image_id);
+    let parent_size = get_volume_size(pool, parent).await?;
+    let size = match create_config.volume_size_in_mib {
+        Some(size) if size < parent_size => {
+            return Err(VmManageError::InvalidArgument(format!(
+                "volume of {} MiB is smaller than volume {} of {} MiB it is cloned from",
+                size, parent, parent_size
+            )))
+        }
+        Some(size) => size,
+        None => parent_size,
+    };
+    let volume_id = create_volume(pool, size, Some(parent), None).await?;
+    rollback.push(Undo::DeleteVolume(volume_id));
+    let volume_path = PathBuf::from(attach_volume(pool, volume_id).await?);
+    rollback.push(Undo::DetachVolume(volume_id));
+    if let Some(drives) = config.drives.as_mut() {
+        drives
+            .iter_mut()
+            .filter(|drive| drive.is_root_device)
+            .for_each(|drive| {
+                drive.partuuid = Some(volume_id.to_string());
+                drive.path_on_host = volume_path.to_owned();
+            });
+    }
+
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+
+    /* The snapshot pins the tap names and the device ids of the NICs. The
+    clone gets a namespace of its own with taps of the same names, leased
+    and addressed anew. */
+    let source_nics = config.network_interfaces.take().unwrap_or_default();
+    let nics = nic_configs(create_config, source_nics.len());
+    if nics.len() != source_nics.len() {
+        return Err(VmManageError::InvalidArgument(format!(
+            "a clone of vm {} has {} network interfaces",
+            source.vmid,
+            source_nics.len()
+        )));
+    }
+    let netns = match source_nics.is_empty() {
+        true => None,
+        false => Some(add_netns(pool, &mut tx, vmid, rollback).await?),
+    };
+    let mut network_interfaces = Vec::new();
+    let mut nic_metadata = Vec::new();
+    let mut kernel_args = config
+        .kernel_args
+        .as_deref()
+        .unwrap_or(DEFAULT_KERNEL_ARGS)
+        .split_whitespace()
+        .filter(|arg| !arg.starts_with("ip="))
+        .collect::<Vec<_>>()
+        .join(" ");
+    for (index, (nic, source_nic)) in nics.into_iter().zip(&source_nics).enumerate() {
+        /* Keep the limits of the source unless asked otherwise */
+        let nic = NetworkInterfaceConfig {
+            rx_rate_limiter: nic
+                .rx_rate_limiter
+                .or(source_nic.rx_rate_limiter.to_owned()),
+            tx_rate_limiter: nic
+                .tx_rate_limiter
+                .or(source_nic.tx_rate_limiter.to_owned()),
+            ..nic
+        };
+        let host_dev_name = source_nic.host_dev_name.to_string_lossy().to_string();
+        let (network_interface, lease) = add_network_interface(
+            pool,
+            &mut tx,
+            vmid,
+            &nic,
+            netns.as_ref(),
+            Some(&host_dev_name),
+            rollback,
+        )
+        .await?;
+        let iface = format!("eth{}", index);
+        if index == 0 {
+            kernel_args = format!("{} {}", kernel_args, lease.kernel_ip_arg(&iface));
+        }
+        nic_metadata.push(lease.metadata(&iface, network_interface.guest_mac.as_deref()));
+        /* Firecracker knows the device by the id it had in the source */
+        network_interfaces.push(NetworkInterface {
+            iface_id: source_nic.iface_id.to_owned(),
+            ..network_interface
+        });
+    }
+
+    /* The metadata of the source carries its leases, the clone gets its own */
+    let initial_metadata = match (&create_config.initial_metadata, &config.init_metadata) {
+        (Some(metadata), _) => Some(metadata.to_owned()),
+        (None, Some(metadata)) if !source_nics.is_empty() => {
+            let mut metadata = serde_json::from_str::<serde_json::Value>(metadata)?;
+            if let Some(object) = metadata.as_object_mut() {
+                object.remove("network");
+            }
+            Some(metadata.to_string())
+        }
+        (None, metadata) => metadata.to_owned(),
+    };
+    config.init_metadata = with_nic_metadata(&initial_metadata, nic_metadata)?;
+    config.kernel_args = Some(kernel_args);
+    config.network_interfaces = Some(network_interfaces);
+
+    let drive_paths = [(ROOT_DRIVE_ID.to_string(), volume_path)];
+    let machine =
+        load_vm_mem_snapshot(vmid, config, &snapshot, &drive_paths, true, netns.as_ref()).await?;
+
+    /* Dump to machine core, without the namespace rustcracker made up */
+    let mut core = machine
+        .dump_into_core()
+        .map_err(VmManageError::MachineDumpCore)?;
+    core.cfg.net_ns = None;
+    rollback.push(Undo::KillVmm(core.pid));
+
+    /* The guest resumes with the addresses and limits of the source, patch
+    its network interfaces and hand it its leases through the metadata */
+    if !source_nics.is_empty() {
+        for network_interface in core.cfg.network_interfaces.iter().flatten() {
+            machine
+                .update_guest_network_interface_rate_limit(
+                    network_interface.iface_id.to_owned(),
+                    RateLimiterSet {
+                        in_rate_limiter: Some(
+                            network_interface
+                                .rx_rate_limiter
+                                .to_owned()
+                                .unwrap_or_else(unlimited_rate_limiter),
+                        ),
+                        out_rate_limiter: Some(
+                            network_interface
+                                .tx_rate_limiter
+                                .to_owned()
+                                .unwrap_or_else(unlimited_rate_limiter),
+                        ),
+                    },
+                )
+                .await
+                .map_err(VmManageError::MachineUpdate)?;
+        }
+        if let Some(metadata) = &core.cfg.init_metadata {
+            machine
+                .update_metadata(metadata)
+                .await
+                .map_err(VmManageError::MachineMetadata)?;
+        }
+    }
+
+    /* Add the creating config to database */
+    pool.add_create_config_db(&mut tx, vmid, create_config)
+        .await?;
+
+    /* Add core to database */
+    pool.add_core_db(&mut tx, vmid, &core, RUNNING).await?;
+
+    /* Add volume to database */
+    pool.add_volume_db(&mut tx, vmid, volume_id, ROOT_DRIVE_ID, image)
+        .await?;
+
+    tx.commit().await.map_err(VmManageError::DBTransaction)?;
+
+    log::trace!("Created vm {} from vm/mem snapshot", vmid);
+    Ok(vmid)
+}
+
+/// Probe the firecracker process of a recorded machine and bring its
+/// status in database in line with what the process reports.
+/// Returns whether the process is still alive.
+async fn restore_vm(pool: &mut VmPool, element: PgMachineCoreElement) -> VmManageResult<bool> {
+    let vmid = element.vmid;
+    log::trace!("Restoring vm {}", vmid);
+    let machine = Machine::rebuild(element.core.0).map_err(VmManageError::MachineRebuild)?;
+
+    let (alive, status) = match machine.describe_instance_info().await {
+        Ok(info) => match info.state {
+            State::NotStarted => (true, CREATED),
+            State::Running => (true, RUNNING),
+            State::Paused => (true, PAUSED),
+        },
+        /* Process is gone, a machine that ever booted is now stopped */
+        Err(_) => match element.status {
+            RUNNING | PAUSED => (false, STOPPED),
+            status => (false, status),
+        },
+    };
+
+    if status != element.status {
+        log::info!(
+            "Vm {} recorded as {} but found {}, reconciling",
+            vmid,
+            element.status,
+            status
+        );
+        let core = machine
+            .dump_into_core()
+            .map_err(VmManageError::MachineDumpCore)?;
+        pool.update_core_db(vmid, &core, status).await?;
+    }
+
+    /* Firewall tables and port forwards do not outlive a reboot of the host */
+    apply_firewall(pool, vmid).await?;
+    for forward in pool.get_port_forward_db(vmid).await? {
+        add_port_forward(&forward).await?;
+    }
+
+    Ok(alive)
+}
+
+/// Re-adopt every vm recorded by this pool, e.g. after a restart of the manager.
+/// Returns the vms whose firecracker process is still alive.
+pub async fn restore_all_vm(pool: &mut VmPool) -> VmManageResult<Vec<Uuid>> {
+    log::trace!("Restoring all vms of pool {}", pool.pool_id);
+    let elements = pool.get_core_all_db().await?;
+
+    let mut restored = Vec::new();
+    for element in elements {
+        let vmid = element.vmid;
+        if element.status == DELETED {
+            continue;
+        }
+        let mut pool_guard = pool.lock(vmid).await?;
+        match restore_vm(pool_guard.pool(), element).await {
+            Ok(true) => restored.push(vmid),
+            Ok(false) => log::warn!("Vm {} has no running firecracker process", vmid),
+            Err(e) => log::error!("Fail to restore vm {}: {}", vmid, e.report()),
+        }
+    }
+
+    log::info!("Restored {} vms", restored.len());
+    Ok(restored)
+}
+
 pub async fn start_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
-    log::trace!("Starting vm");
+    log::trace!("Starting vm {}", vmid);
+    pool.check_transition(vmid, Transition::Start).await?;
     let mut machine = get_vm(pool, vmid).await?;
-    machine
-        .start()
-        .await
-        .map_err(|_| VmManageError::MachineStart)?;
+    let netns = vm_netns(pool, vmid).await?;
+    set_netns_command(&mut machine, netns.as_ref());
+    machine.start().await.map_err(VmManageError::MachineStart)?;
     let core = machine
         .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
-    pool.update_core_db(vmid, &core, RUNNING).await?;
+        .map_err(VmManageError::MachineDumpCore)?;
+    pool.update_core_db(vmid, &core, Transition::Start.target_state())
+        .await?;
     Ok(())
 }
 
 pub async fn pause_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
     log::trace!("Pausing vm {}", vmid);
+    pool.check_transition(vmid, Transition::Pause).await?;
     let machine = get_vm(pool, vmid).await?;
-    machine
-        .pause()
-        .await
-        .map_err(|_| VmManageError::MachinePause)?;
+    machine.pause().await.map_err(VmManageError::MachinePause)?;
     let core = machine
         .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
-    pool.update_core_db(vmid, &core, PAUSED).await?;
+        .map_err(VmManageError::MachineDumpCore)?;
+    pool.update_core_db(vmid, &core, Transition::Pause.target_state())
+        .await?;
     Ok(())
 }
 
 pub async fn resume_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
     log::trace!("Resuming vm {}", vmid);
+    pool.check_transition(vmid, Transition::Resume).await?;
     let machine = get_vm(pool, vmid).await?;
     machine
         .resume()
         .await
-        .map_err(|_| VmManageError::MachineResume)?;
+        .map_err(VmManageError::MachineResume)?;
+    let core = machine
+        .dump_into_core()
+        .map_err(VmManageError::MachineDumpCore)?;
+    pool.update_core_db(vmid, &core, Transition::Resume.target_state())
+        .await?;
+    Ok(())
+}
+
+pub async fn stop_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
+    log::trace!("Stopping vm {}", vmid);
+    pool.check_transition(vmid, Transition::Stop).await?;
+    let machine = get_vm(pool, vmid).await?;
+    machine
+        .shutdown()
+        .await
+        .map_err(VmManageError::MachineStop)?;
     let core = machine
         .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
-    pool.update_core_db(vmid, &core, RUNNING).await?;
+        .map_err(VmManageError::MachineDumpCore)?;
+    pool.update_core_db(vmid, &core, Transition::Stop.target_state())
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
+    log::trace!("Deleting vm {}", vmid);
+    pool.check_transition(vmid, Transition::Delete).await?;
+    let status = pool.get_status_db(vmid).await?;
+    let core = pool.get_core_db(vmid).await?;
+
+    /* A running or paused vm has a vmm to tear down. The one of a stopped vm
+    may have outlived a failed stop, but its pid may as well be reused. */
+    if status == RUNNING || status == PAUSED {
+        let machine = Machine::rebuild(core.to_owned()).map_err(VmManageError::MachineRebuild)?;
+        let _ = machine.shutdown().await;
+        kill_vmm(core.pid).await?;
+    } else if is_own_vmm(&core).await {
+        log::info!(
+            "Vm {} left firecracker {} behind, killing it",
+            vmid,
+            core.pid
+        );
+        kill_vmm(core.pid).await?;
+    }
+    if status != DELETED {
+        pool.update_core_db(vmid, &core, Transition::Delete.target_state())
+            .await?;
+    }
+
+    /* Get every volume_id from database */
+    let volume_ids = pool.get_volume_id(vmid).await?;
+    let disk_snapshots = pool.get_disk_snapshot_all_db(vmid).await?;
+
+    /* Get every tap_id from database */
+    let tap_ids = pool.get_tap_id(vmid).await?;
+    let host_dev_names = host_dev_names(&core.cfg);
+    let netns = vm_netns(pool, vmid).await?;
+    let port_forwards = pool.get_port_forward_db(vmid).await?;
+
+    let mut rollback = Rollback::default();
+    match do_delete_vm(pool, vmid, &volume_ids, &mut rollback).await {
+        Ok(()) => {}
+        Err(e) => {
+            log::error!("Fail to delete vm {}: {}, rolling back", vmid, e.report());
+            rollback.run(pool).await;
+            return Err(e);
+        }
+    }
+
+    /* Delete volumes with their snapshots, past the point of no return */
+    for volume_snapshot in disk_snapshots.iter().flat_map(|snapshot| &snapshot.volumes) {
+        if let Err(e) = delete_volume_snapshot(
+            pool,
+            volume_snapshot.volume_id,
+            volume_snapshot.volume_snapshot_id,
+        )
+        .await
+        {
+            log::error!(
+                "Fail to delete snapshot {} of volume {} of vm {}: {}",
+                volume_snapshot.volume_snapshot_id,
+                volume_snapshot.volume_id,
+                vmid,
+                e.report()
+            );
+        }
+    }
+    for volume_id in volume_ids {
+        if let Err(e) = delete_volume(pool, volume_id).await {
+            log::error!(
+                "Fail to delete volume {} of vm {}: {}",
+                volume_id,
+                vmid,
+                e.report()
+            );
+        }
+    }
+
+    /* Tear down port forwards, taps with their firewall and iptables rules */
+    for forward in port_forwards {
+        if let Err(e) = delete_port_forward(&forward).await {
+            log::error!(
+                "Fail to delete port forward {}/{} of vm {}: {}",
+                forward.host_port,
+                forward.protocol.as_str(),
+                vmid,
+                e.report()
+            );
+        }
+    }
+    let netns_name = netns.as_ref().map(|netns| netns.name.as_str());
+    for host_dev_name in host_dev_names {
+        if let Err(e) = firewall::remove(netns_name, &host_dev_name).await {
+            log::error!(
+                "Fail to remove firewall of {} of vm {}: {}",
+                host_dev_name,
+                vmid,
+                e.report()
+            );
+        }
+    }
+    for tap_id in tap_ids {
+        if let Err(e) = delete_network_interface(pool, vmid, tap_id).await {
+            log::error!(
+                "Fail to delete tap{} of vm {}: {}",
+                tap_id,
+                vmid,
+                e.report()
+            );
+        }
+    }
+    if let Some(netns) = netns {
+        if let Err(e) = delete_netns(&netns).await {
+            log::error!(
+                "Fail to delete network namespace {} of vm {}: {}",
+                netns.name,
+                vmid,
+                e.report()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the records of a vm and detach its volumes, all or nothing
+async fn do_delete_vm(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    volume_ids: &[Uuid],
+    rollback: &mut Rollback,
+) -> VmManageResult<()> {
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+
+    /* Remove settings from db */
+    /* Delete the creating config to database */
+    pool.delete_create_config_db(&mut tx, vmid).await?;
+
+    /* Delete core from database */
+    pool.delete_core_db(&mut tx, vmid).await?;
+
+    /* Release taps, their leases and security groups */
+    pool.delete_network_db(&mut tx, vmid).await?;
+    pool.delete_ip_lease_db(&mut tx, vmid).await?;
+    pool.delete_security_group_binding_by_vmid_db(&mut tx, vmid)
+        .await?;
+    pool.delete_port_forward_db(&mut tx, vmid).await?;
+    pool.delete_disk_snapshot_by_vmid_db(&mut tx, vmid).await?;
+    pool.delete_netns_db(&mut tx, vmid).await?;
+
+    for &volume_id in volume_ids {
+        /* Delete volume from database */
+        pool.delete_volume_db(&mut tx, vmid, volume_id).await?;
+
+        /* Detach */
+        detach_volume(pool, volume_id).await?;
+        rollback.push(Undo::AttachVolume(volume_id));
+    }
+
+    tx.commit().await.map_err(VmManageError::DBTransaction)?;
+
+    Ok(())
+}
+
+pub async fn modify_metadata(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    metadata: &String,
+) -> VmManageResult<()> {
+    log::trace!("Modifying metadata of {}", vmid);
+    let machine = get_vm(pool, vmid).await?;
+    machine
+        .update_metadata(metadata)
+        .await
+        .map_err(VmManageError::MachineMetadata)?;
     Ok(())
 }
 
-pub async fn stop_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
-    log::trace!("Stopping vm {}", vmid);
-    let machine = get_vm(pool, vmid).await?;
-    machine
-        .shutdown()
-        .await
-        .map_err(|_| VmManageError::MachineStop)?;
-    let core = machine
-        .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
-    pool.update_core_db(vmid, &core, STOPPED).await?;
+/// Buckets of size 0 do not limit, firecracker lifts a limiter patched with them
+fn unlimited_rate_limiter() -> RateLimiter {
+    let unlimited = TokenBucket {
+        one_time_burst: None,
+        refill_time: 0,
+        size: 0,
+    };
+    RateLimiter::new(unlimited.to_owned(), unlimited)
+}
+
+/// Change the rate limits of a network interface, live if the vm is running.
+/// `Some(None)` lifts a limiter.
+pub async fn update_network_rate_limit(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    iface_id: &str,
+    rx_rate_limiter: Option<Option<RateLimiter>>,
+    tx_rate_limiter: Option<Option<RateLimiter>>,
+) -> VmManageResult<NetworkInterface> {
+    log::trace!("Updating rate limits of {} of vm {}", iface_id, vmid);
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "update network of".to_string(),
+            status,
+        ));
+    }
+
+    let mut core = pool.get_core_db(vmid).await?;
+    let mut network_interfaces = core.cfg.network_interfaces.take().unwrap_or_default();
+    let network_interface = network_interfaces
+        .iter_mut()
+        .find(|network_interface| network_interface.iface_id == iface_id)
+        .ok_or_else(|| VmManageError::NetworkInterfaceNotFound(vmid, iface_id.to_string()))?;
+
+    /* A running vmm takes the new token buckets at once */
+    if status == RUNNING || status == PAUSED {
+        let machine = Machine::rebuild(core.to_owned()).map_err(VmManageError::MachineRebuild)?;
+        machine
+            .update_guest_network_interface_rate_limit(
+                iface_id.to_string(),
+                RateLimiterSet {
+                    in_rate_limiter: rx_rate_limiter
+                        .to_owned()
+                        .map(|limiter| limiter.unwrap_or_else(unlimited_rate_limiter)),
+                    out_rate_limiter: tx_rate_limiter
+                        .to_owned()
+                        .map(|limiter| limiter.unwrap_or_else(unlimited_rate_limiter)),
+                },
+            )
+            .await
+            .map_err(VmManageError::MachineUpdate)?;
+    }
+
+    /* Persist the effective limits, applied again on the next boot */
+    if let Some(rx_rate_limiter) = rx_rate_limiter {
+        network_interface.rx_rate_limiter = rx_rate_limiter;
+    }
+    if let Some(tx_rate_limiter) = tx_rate_limiter {
+        network_interface.tx_rate_limiter = tx_rate_limiter;
+    }
+    let network_interface = network_interface.to_owned();
+    update_network_interface(
+        pool,
+        vmid,
+        iface_id,
+        network_interface.rx_rate_limiter.to_owned(),
+        network_interface.tx_rate_limiter.to_owned(),
+    )
+    .await?;
+    core.cfg.network_interfaces = Some(network_interfaces);
+    pool.update_core_db(vmid, &core, status).await?;
+
+    Ok(network_interface)
+}
+
+/// Render the rules of every security group of a vm onto each of its taps
+async fn apply_firewall(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
+    let mut rules = Vec::new();
+    for group_id in pool.get_security_group_id_by_vmid_db(vmid).await? {
+        rules.extend(pool.get_security_group_db(group_id).await?.rules);
+    }
+    let config = pool.get_core_db(vmid).await?.cfg;
+    let netns = vm_netns(pool, vmid).await?;
+    let netns_name = netns.as_ref().map(|netns| netns.name.as_str());
+    for host_dev_name in host_dev_names(&config) {
+        firewall::apply(netns_name, &host_dev_name, &rules).await?;
+    }
     Ok(())
 }
 
-pub async fn delete_vm(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<()> {
-    log::trace!("Deleting vm {}", vmid);
-    let mut machine = get_vm(pool, vmid).await?;
-    let _ = machine.shutdown().await;
-    machine
-        .stop_vmm()
+/// Forward a free host port to `guest_port` of the primary interface of a vm
+pub async fn create_port_forward(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    protocol: ForwardProtocol,
+    guest_port: u16,
+) -> VmManageResult<PortForward> {
+    log::trace!(
+        "Forwarding {}/{} of vm {}",
+        guest_port,
+        protocol.as_str(),
+        vmid
+    );
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "forward a port to".to_string(),
+            status,
+        ));
+    }
+    if guest_port == 0 {
+        return Err(VmManageError::InvalidArgument(
+            "guest port must not be 0".to_string(),
+        ));
+    }
+    /* The DNAT happens in the root namespace, which has no route to a guest
+     * living behind its own namespace */
+    if pool.get_netns_db(vmid).await?.is_some() {
+        return Err(VmManageError::InvalidArgument(format!(
+            "vm {} lives in a network namespace, port forwarding is not supported",
+            vmid
+        )));
+    }
+    let lease = pool
+        .get_ip_lease_db(vmid)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| VmManageError::NetworkInterfaceNotFound(vmid, "eth0".to_string()))?;
+
+    let mut tx = pool
+        .conn
+        .begin()
         .await
-        .map_err(|_| VmManageError::MachineStop)?;
-    let core = machine
-        .dump_into_core()
-        .map_err(|_| VmManageError::MachineDumpCore)?;
-    pool.update_core_db(vmid, &core, DELETED).await?;
-
-    /* Remove settings from db */
-    /* Delete the creating config to database */
-    pool.delete_create_config_db(vmid).await?;
+        .map_err(VmManageError::DBTransaction)?;
+    let forward = PortForward {
+        protocol,
+        host_port: pool.allocate_host_port(&mut tx, protocol).await?,
+        guest_ip: lease.guest_ip(),
+        guest_port,
+    };
+    pool.add_port_forward_db(&mut tx, vmid, &forward).await?;
 
-    /* Delete core from database */
-    pool.delete_core_db(vmid).await?;
+    add_port_forward(&forward).await?;
+    if let Err(e) = tx.commit().await {
+        let _ = delete_port_forward(&forward).await;
+        return Err(VmManageError::DBTransaction(e));
+    }
 
-    /* Get every volume_id from database */
-    let volume_ids = pool.get_volume_id(vmid).await?;
+    Ok(forward)
+}
 
-    /* Delete volume from database */
-    for volume_id in volume_ids {
-        pool.delete_volume_db(vmid, volume_id).await?;
+pub async fn create_security_group(
+    pool: &mut VmPool,
+    name: &String,
+    rules: &Vec<SecurityGroupRule>,
+) -> VmManageResult<Uuid> {
+    rules.iter().try_for_each(firewall::validate_rule)?;
+    let id = Uuid::new_v4();
+    pool.add_security_group_db(id, name, rules).await?;
+    Ok(id)
+}
 
-        /* Detach */
-        detach_volume(pool, volume_id).await?;
+pub async fn list_security_group(pool: &mut VmPool) -> VmManageResult<Vec<SecurityGroup>> {
+    pool.get_security_group_all_db().await
+}
 
-        /* Delete */
-        delete_volume(pool, volume_id).await?;
+pub async fn delete_security_group(pool: &mut VmPool, id: Uuid) -> VmManageResult<()> {
+    pool.get_security_group_db(id).await?;
+    let vmids = pool.get_vmid_by_security_group_db(id).await?;
+    if !vmids.is_empty() {
+        return Err(VmManageError::SecurityGroupInUse(id, vmids));
     }
+    pool.delete_security_group_db(id).await
+}
 
-    Ok(())
+pub async fn attach_security_group(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    group_id: Uuid,
+) -> VmManageResult<Vec<Uuid>> {
+    log::trace!("Attaching security group {} to vm {}", group_id, vmid);
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "attach security group to".to_string(),
+            status,
+        ));
+    }
+    pool.get_security_group_db(group_id).await?;
+    pool.add_security_group_binding_db(vmid, group_id).await?;
+    if let Err(e) = apply_firewall(pool, vmid).await {
+        pool.delete_security_group_binding_db(vmid, group_id)
+            .await?;
+        return Err(e);
+    }
+    pool.get_security_group_id_by_vmid_db(vmid).await
 }
 
-pub async fn modify_metadata(
+pub async fn detach_security_group(
     pool: &mut VmPool,
     vmid: Uuid,
-    metadata: &String,
-) -> VmManageResult<()> {
-    log::trace!("Modifying metadata of {}", vmid);
-    let machine = get_vm(pool, vmid).await?;
-    machine
-        .update_metadata(metadata)
-        .await
-        .map_err(|_| VmManageError::MachinePause)?;
-    Ok(())
+    group_id: Uuid,
+) -> VmManageResult<Vec<Uuid>> {
+    log::trace!("Detaching security group {} from vm {}", group_id, vmid);
+    pool.get_status_db(vmid).await?;
+    pool.delete_security_group_binding_db(vmid, group_id)
+        .await?;
+    apply_firewall(pool, vmid).await?;
+    pool.get_security_group_id_by_vmid_db(vmid).await
 }
 
 pub async fn get_vm_status(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<VmViewInfo> {
@@ -727,22 +2824,56 @@ pub async fn get_vm_status(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<VmVi
     let full_config = machine
         .get_export_vm_config()
         .await
-        .map_err(|_| VmManageError::MachineQuery)?;
+        .map_err(VmManageError::MachineQuery)?;
     let vm_info = machine
         .describe_instance_info()
         .await
-        .map_err(|_| VmManageError::MachineQuery)?;
+        .map_err(VmManageError::MachineQuery)?;
     let boot_config = machine.get_config();
+    let guest_ips = pool
+        .get_ip_lease_db(vmid)
+        .await?
+        .iter()
+        .map(IpLease::guest_ip)
+        .collect();
     let vm_status = VmViewInfo {
         vmid,
         vm_info,
         full_config,
         boot_config,
+        guest_ips,
     };
 
     Ok(vm_status)
 }
 
+pub async fn list_vm(pool: &mut VmPool, request: &VmListRequest) -> VmManageResult<VmListResponse> {
+    log::trace!("Listing vms");
+    let status =
+        match &request.status {
+            Some(name) => Some(machine_state_from_name(name).ok_or(
+                VmManageError::InvalidArgument(format!("unknown status {name}")),
+            )?),
+            None => None,
+        };
+    let limit = request.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    if limit <= 0 {
+        return Err(VmManageError::InvalidArgument(format!(
+            "limit must be positive, got {limit}"
+        )));
+    }
+
+    let vms = pool
+        .list_vm_db(status, request.kernel.as_ref(), request.cursor, limit)
+        .await?;
+    let next_cursor = match vms.len() as i64 == limit {
+        true => vms.last().map(|vm| vm.vmid),
+        false => None,
+    };
+
+    Ok(VmListResponse { vms, next_cursor })
+}
+
 pub async fn create_vm_mem_snapshot(pool: &mut VmPool, vmid: Uuid) -> VmManageResult<Uuid> {
     log::trace!("Creating vm/mem snapshot for {}", vmid);
     let vm_mem_snapshot_id = Uuid::new_v4();
@@ -750,21 +2881,594 @@ pub async fn create_vm_mem_snapshot(pool: &mut VmPool, vmid: Uuid) -> VmManageRe
     let vm_snapshot_path = pool.vm_snapshot_path(vmid, vm_mem_snapshot_id);
 
     let machine = get_vm(pool, vmid).await?;
+    let status = pool.get_status_db(vmid).await?;
     machine
         .create_snapshot(&mem_snapshot_path, &vm_snapshot_path)
         .await
-        .map_err(|_| VmManageError::VmMemSnapshotCreate)?;
+        .map_err(VmManageError::VmMemSnapshotCreate)?;
     pool.add_vm_mem_snapshot_db(
         vmid,
         vm_mem_snapshot_id,
         &mem_snapshot_path,
         &vm_snapshot_path,
+        status,
     )
     .await?;
 
     Ok(vm_mem_snapshot_id)
 }
 
+/// Group the rows of disk snapshots by snapshot, in the order of the rows
+fn disk_snapshot_infos(elements: Vec<PgDiskSnapshotElement>) -> Vec<DiskSnapshotInfo> {
+    let mut infos: Vec<DiskSnapshotInfo> = Vec::new();
+    for element in elements {
+        let volume_snapshot = VolumeSnapshot {
+            volume_id: element.volume_id,
+            volume_snapshot_id: element.volume_snapshot_id,
+        };
+        match infos
+            .iter_mut()
+            .find(|info| info.snapshot_id == element.snapshot_id)
+        {
+            Some(info) => info.volumes.push(volume_snapshot),
+            None => infos.push(DiskSnapshotInfo {
+                snapshot_id: element.snapshot_id,
+                volumes: vec![volume_snapshot],
+                created_at: element.created_at,
+            }),
+        }
+    }
+    infos
+}
+
+/// Snapshot every volume of a vm. Taken from a running vm it is only crash consistent.
+pub async fn create_disk_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+) -> VmManageResult<DiskSnapshotInfo> {
+    log::trace!("Creating disk snapshot for {}", vmid);
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "snapshot the disks of".to_string(),
+            status,
+        ));
+    }
+
+    let snapshot_id = Uuid::new_v4();
+    let mut volumes = Vec::new();
+    for volume_id in pool.get_volume_id(vmid).await? {
+        match create_volume_snapshot(pool, volume_id).await {
+            Ok(volume_snapshot_id) => volumes.push(VolumeSnapshot {
+                volume_id,
+                volume_snapshot_id,
+            }),
+            Err(e) => {
+                for taken in volumes {
+                    let _ = delete_volume_snapshot(pool, taken.volume_id, taken.volume_snapshot_id)
+                        .await;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+    for volume_snapshot in volumes.iter() {
+        pool.add_disk_snapshot_db(&mut tx, vmid, snapshot_id, volume_snapshot)
+            .await?;
+    }
+    tx.commit().await.map_err(VmManageError::DBTransaction)?;
+
+    /* Read back for the timestamp */
+    pool.get_disk_snapshot_db(vmid, snapshot_id).await
+}
+
+pub async fn list_disk_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+) -> VmManageResult<Vec<DiskSnapshotInfo>> {
+    log::trace!("Listing disk snapshots of {}", vmid);
+    pool.get_status_db(vmid).await?;
+    pool.get_disk_snapshot_all_db(vmid).await
+}
+
+/// The snapshot of the root volume in a disk snapshot of a vm
+async fn root_volume_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    snapshot_id: Uuid,
+) -> VmManageResult<(MachineCore, VolumeSnapshot)> {
+    let snapshot = pool.get_disk_snapshot_db(vmid, snapshot_id).await?;
+    let core = pool.get_core_db(vmid).await?;
+    let root = pool.get_drive_volume_db(vmid, ROOT_DRIVE_ID).await?;
+    let volume_snapshot = snapshot
+        .volumes
+        .into_iter()
+        .find(|volume_snapshot| volume_snapshot.volume_id == root)
+        .ok_or_else(|| {
+            VmManageError::InvalidArgument(format!(
+                "disk snapshot {} holds no snapshot of root volume {}",
+                snapshot_id, root
+            ))
+        })?;
+    Ok((core, volume_snapshot))
+}
+
+/// Roll the root volume of a vm that is not running back to a disk snapshot
+pub async fn rollback_disk_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    snapshot_id: Uuid,
+) -> VmManageResult<()> {
+    log::trace!("Rolling vm {} back to disk snapshot {}", vmid, snapshot_id);
+    let status = pool.get_status_db(vmid).await?;
+    if status != CREATED && status != STOPPED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "roll back the disk of".to_string(),
+            status,
+        ));
+    }
+    let (mut core, volume_snapshot) = root_volume_snapshot(pool, vmid, snapshot_id).await?;
+    let VolumeSnapshot {
+        volume_id,
+        volume_snapshot_id,
+    } = volume_snapshot;
+    if !list_volume_snapshot(pool, volume_id)
+        .await?
+        .contains(&volume_snapshot_id)
+    {
+        return Err(VmManageError::DiskSnapshotNotFound(snapshot_id));
+    }
+
+    /* The storage manager only rolls back detached volumes */
+    detach_volume(pool, volume_id).await?;
+    let rolled_back = rollback_volume_snapshot(pool, volume_id, volume_snapshot_id).await;
+    let volume_path = attach_volume(pool, volume_id).await?;
+    rolled_back?;
+
+    /* The device may come back under another path */
+    if let Some(drives) = core.cfg.drives.as_mut() {
+        drives
+            .iter_mut()
+            .filter(|drive| drive.drive_id == ROOT_DRIVE_ID)
+            .for_each(|drive| drive.path_on_host = PathBuf::from(&volume_path));
+    }
+    pool.update_core_db(vmid, &core, status).await?;
+
+    log::trace!("Rolled vm {} back to disk snapshot {}", vmid, snapshot_id);
+    Ok(())
+}
+
+/// Fail with `InvalidTransition` unless the vmm of the vm is down, which is
+/// when drives could be added or removed
+async fn check_drives_editable(
+    pool: &VmPool,
+    vmid: Uuid,
+    operation: &str,
+) -> VmManageResult<MachineState> {
+    let status = pool.get_status_db(vmid).await?;
+    match status == CREATED || status == STOPPED {
+        true => Ok(status),
+        false => Err(VmManageError::InvalidTransition(
+            vmid,
+            operation.to_string(),
+            status,
+        )),
+    }
+}
+
+/// First `dataN` drive id the config does not use yet
+fn next_data_drive_id(config: &Config) -> String {
+    let drives = config.drives.as_deref().unwrap_or_default();
+    (0..)
+        .map(|n| format!("data{}", n))
+        .find(|drive_id| drives.iter().all(|drive| &drive.drive_id != drive_id))
+        .unwrap()
+}
+
+/// Add a data volume to a stopped vm as one more drive, either a new volume
+/// or an existing one attached to no vm. Like the root volume it is deleted
+/// along with the vm. Returns (volume_id, drive_id).
+pub async fn attach_vm_volume(
+    pool: &mut VmPool,
+    request: &VmVolumeAttachRequest,
+) -> VmManageResult<(Uuid, String)> {
+    let vmid = request.vmid;
+    log::trace!("Attaching volume to vm {}", vmid);
+    let mut rollback = Rollback::default();
+    match do_attach_vm_volume(pool, request, &mut rollback).await {
+        Ok(attached) => Ok(attached),
+        Err(e) => {
+            log::error!(
+                "Fail to attach volume to vm {}: {}, rolling back",
+                vmid,
+                e.report()
+            );
+            rollback.run(pool).await;
+            Err(e)
+        }
+    }
+}
+
+async fn do_attach_vm_volume(
+    pool: &mut VmPool,
+    request: &VmVolumeAttachRequest,
+    rollback: &mut Rollback,
+) -> VmManageResult<(Uuid, String)> {
+    let vmid = request.vmid;
+    let status = check_drives_editable(pool, vmid, "attach volume to").await?;
+    let mut core = pool.get_core_db(vmid).await?;
+
+    /* Request for a new volume or take over an existing one */
+    let volume_id = match (request.volume_id, request.size_in_mib) {
+        (Some(volume_id), None) => {
+            if let Some(owner) = pool.get_volume_owner_db(volume_id).await? {
+                return Err(VmManageError::VolumeInUse(volume_id, owner));
+            }
+            volume_id
+        }
+        (None, Some(size)) if size > 0 => {
+            let volume_id = create_volume(pool, size, None, None).await?;
+            rollback.push(Undo::DeleteVolume(volume_id));
+            volume_id
+        }
+        (None, Some(size)) => {
+            return Err(VmManageError::InvalidArgument(format!(
+                "invalid volume size {} MiB",
+                size
+            )))
+        }
+        _ => {
+            return Err(VmManageError::InvalidArgument(
+                "exactly one of volume_id and size_in_mib is required".to_string(),
+            ))
+        }
+    };
+    let volume_path = attach_volume(pool, volume_id).await?;
+    rollback.push(Undo::DetachVolume(volume_id));
+
+    let drive_id = next_data_drive_id(&core.cfg);
+    let drive = Drive {
+        drive_id: drive_id.to_owned(),
+        partuuid: None,
+        is_root_device: false,
+        cache_type: request.cache_type,
+        is_read_only: request.is_read_only.unwrap_or(false),
+        path_on_host: PathBuf::from(volume_path),
+        rate_limiter: request.rate_limiter.to_owned(),
+        io_engine: request.io_engine,
+        socket: None,
+    };
+    core.cfg.drives.get_or_insert_with(Vec::new).push(drive);
+
+    /* Record the drive and the config it boots with together */
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+    pool.add_volume_db(&mut tx, vmid, volume_id, &drive_id, None)
+        .await?;
+    pool.update_core_tx_db(&mut tx, vmid, &core, status).await?;
+    tx.commit().await.map_err(VmManageError::DBTransaction)?;
+
+    log::trace!(
+        "Attached volume {} to vm {} as {}",
+        volume_id,
+        vmid,
+        drive_id
+    );
+    Ok((volume_id, drive_id))
+}
+
+/// Remove a data drive from a stopped vm, deleting its volume if asked to.
+/// Returns the volume_id of the drive.
+pub async fn detach_vm_volume(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    drive_id: &str,
+    delete: bool,
+) -> VmManageResult<Uuid> {
+    log::trace!("Detaching drive {} of vm {}", drive_id, vmid);
+    let status = check_drives_editable(pool, vmid, "detach volume from").await?;
+    if drive_id == ROOT_DRIVE_ID {
+        return Err(VmManageError::InvalidArgument(
+            "the root drive cannot be detached".to_string(),
+        ));
+    }
+    let mut core = pool.get_core_db(vmid).await?;
+    let volume_id = pool.get_drive_volume_db(vmid, drive_id).await?;
+    if let Some(drives) = core.cfg.drives.as_mut() {
+        drives.retain(|drive| drive.drive_id != drive_id);
+    }
+
+    detach_volume(pool, volume_id).await?;
+    let mut rollback = Rollback::default();
+    rollback.push(Undo::AttachVolume(volume_id));
+    let res: VmManageResult<()> = async {
+        let mut tx = pool
+            .conn
+            .begin()
+            .await
+            .map_err(VmManageError::DBTransaction)?;
+        pool.delete_volume_db(&mut tx, vmid, volume_id).await?;
+        pool.update_core_tx_db(&mut tx, vmid, &core, status).await?;
+        tx.commit().await.map_err(VmManageError::DBTransaction)
+    }
+    .await;
+    if let Err(e) = res {
+        log::error!(
+            "Fail to detach drive {} of vm {}: {}, rolling back",
+            drive_id,
+            vmid,
+            e.report()
+        );
+        rollback.run(pool).await;
+        return Err(e);
+    }
+
+    /* The drive is gone already, failing to delete its volume does not undo the detach */
+    if delete {
+        if let Err(e) = delete_volume(pool, volume_id).await {
+            log::error!("Fail to delete volume {}: {}", volume_id, e.report());
+        }
+    }
+
+    log::trace!("Detached volume {} from vm {}", volume_id, vmid);
+    Ok(volume_id)
+}
+
+/// Back a data drive with another volume, live if the vm is running.
+/// Returns the volume_id the drive was backed by.
+pub async fn swap_vm_volume(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    drive_id: &str,
+    volume_id: Uuid,
+    delete_old: bool,
+) -> VmManageResult<Uuid> {
+    log::trace!(
+        "Swapping drive {} of vm {} to volume {}",
+        drive_id,
+        vmid,
+        volume_id
+    );
+    let mut rollback = Rollback::default();
+    let old_volume_id =
+        match do_swap_vm_volume(pool, vmid, drive_id, volume_id, &mut rollback).await {
+            Ok(old_volume_id) => old_volume_id,
+            Err(e) => {
+                log::error!(
+                    "Fail to swap drive {} of vm {}: {}, rolling back",
+                    drive_id,
+                    vmid,
+                    e.report()
+                );
+                rollback.run(pool).await;
+                return Err(e);
+            }
+        };
+
+    /* The drive is off the old volume already, failing to release it does not undo the swap */
+    let released = match detach_volume(pool, old_volume_id).await {
+        Ok(_) if delete_old => delete_volume(pool, old_volume_id).await.map(|_| ()),
+        res => res.map(|_| ()),
+    };
+    if let Err(e) = released {
+        log::error!("Fail to release volume {}: {}", old_volume_id, e.report());
+    }
+
+    log::trace!(
+        "Swapped drive {} of vm {} to volume {}",
+        drive_id,
+        vmid,
+        volume_id
+    );
+    Ok(old_volume_id)
+}
+
+async fn do_swap_vm_volume(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    drive_id: &str,
+    volume_id: Uuid,
+    rollback: &mut Rollback,
+) -> VmManageResult<Uuid> {
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "swap volume of".to_string(),
+            status,
+        ));
+    }
+    if drive_id == ROOT_DRIVE_ID {
+        return Err(VmManageError::InvalidArgument(
+            "the root drive cannot be swapped".to_string(),
+        ));
+    }
+    let old_volume_id = pool.get_drive_volume_db(vmid, drive_id).await?;
+    if let Some(owner) = pool.get_volume_owner_db(volume_id).await? {
+        return Err(VmManageError::VolumeInUse(volume_id, owner));
+    }
+
+    let mut core = pool.get_core_db(vmid).await?;
+    let drive = core
+        .cfg
+        .drives
+        .iter_mut()
+        .flatten()
+        .find(|drive| drive.drive_id == drive_id)
+        .ok_or_else(|| VmManageError::DriveNotFound(vmid, drive_id.to_string()))?;
+    let old_path = drive.path_on_host.to_owned();
+
+    let volume_path = PathBuf::from(attach_volume(pool, volume_id).await?);
+    rollback.push(Undo::DetachVolume(volume_id));
+    drive.path_on_host = volume_path.to_owned();
+
+    /* Stage the new volume, committed only once the vmm took it */
+    let mut tx = pool
+        .conn
+        .begin()
+        .await
+        .map_err(VmManageError::DBTransaction)?;
+    pool.delete_volume_db(&mut tx, vmid, old_volume_id).await?;
+    pool.add_volume_db(&mut tx, vmid, volume_id, drive_id, None)
+        .await?;
+    pool.update_core_tx_db(&mut tx, vmid, &core, status).await?;
+
+    let machine = match status == RUNNING || status == PAUSED {
+        true => {
+            let machine =
+                Machine::rebuild(core.to_owned()).map_err(VmManageError::MachineRebuild)?;
+            machine
+                .update_guest_drive(drive_id.to_string(), volume_path)
+                .await
+                .map_err(VmManageError::MachineUpdate)?;
+            Some(machine)
+        }
+        false => None,
+    };
+
+    if let Err(e) = tx.commit().await {
+        /* Point the vmm back at the old volume, still attached */
+        if let Some(machine) = machine {
+            if let Err(e) = machine
+                .update_guest_drive(drive_id.to_string(), old_path)
+                .await
+            {
+                log::error!("Fail to restore drive {} of vm {}: {}", drive_id, vmid, e);
+            }
+        }
+        return Err(VmManageError::DBTransaction(e));
+    }
+
+    Ok(old_volume_id)
+}
+
+/// Change the rate limiter of a drive, live if the vm is running
+pub async fn update_drive_rate_limit(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    drive_id: &str,
+    rate_limiter: RateLimiter,
+) -> VmManageResult<Drive> {
+    log::trace!("Updating rate limiter of drive {} of vm {}", drive_id, vmid);
+    let status = pool.get_status_db(vmid).await?;
+    if status == DELETED {
+        return Err(VmManageError::InvalidTransition(
+            vmid,
+            "update drive of".to_string(),
+            status,
+        ));
+    }
+
+    let mut core = pool.get_core_db(vmid).await?;
+    let mut drives = core.cfg.drives.take().unwrap_or_default();
+    let drive = drives
+        .iter_mut()
+        .find(|drive| drive.drive_id == drive_id)
+        .ok_or_else(|| VmManageError::DriveNotFound(vmid, drive_id.to_string()))?;
+
+    /* Machine only patches the path of a drive, talk to the vmm directly */
+    if status == RUNNING || status == PAUSED {
+        let agent = Agent::new(
+            &core.socket_path,
+            core.firecracker_request_timeout,
+            core.firecracker_init_timeout,
+        );
+        agent
+            .patch_guest_drive_by_id(&PartialDrive {
+                drive_id: drive_id.to_string(),
+                path_on_host: None,
+                rate_limiter: Some(rate_limiter.to_owned()),
+            })
+            .await
+            .map_err(|e| {
+                VmManageError::MachineUpdate(MachineError::Agent(format!(
+                    "PatchGuestDrive failed: {}",
+                    e
+                )))
+            })?;
+    }
+
+    /* Persist the limiter, applied again on the next boot */
+    drive.rate_limiter = Some(rate_limiter);
+    let drive = drive.to_owned();
+    core.cfg.drives = Some(drives);
+    pool.update_core_db(vmid, &core, status).await?;
+
+    Ok(drive)
+}
+
+/// Publish the root volume of a disk snapshot as an image
+pub async fn publish_disk_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    snapshot_id: Uuid,
+) -> VmManageResult<Uuid> {
+    log::trace!("Publishing disk snapshot {} of {}", snapshot_id, vmid);
+    let (_, volume_snapshot) = root_volume_snapshot(pool, vmid, snapshot_id).await?;
+    publish_image(
+        pool,
+        volume_snapshot.volume_id,
+        volume_snapshot.volume_snapshot_id,
+    )
+    .await
+}
+
+fn vm_mem_snapshot_info(element: PgVmMemSnapshotElement) -> SnapshotInfo {
+    let file_size = |path: &String| std::fs::metadata(path).map(|m| m.len()).ok();
+    SnapshotInfo {
+        snapshot_id: element.snapshot_id,
+        snapshot_size: file_size(&element.snapshot_path),
+        memory_size: file_size(&element.mem_file_path),
+        snapshot_path: element.snapshot_path,
+        memory_path: element.mem_file_path,
+        status: element.status,
+        created_at: element.created_at,
+    }
+}
+
+pub async fn list_vm_mem_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+) -> VmManageResult<Vec<SnapshotInfo>> {
+    log::trace!("Listing vm/mem snapshots of {}", vmid);
+    let snapshots = pool
+        .get_vm_mem_snapshot_all_db(vmid)
+        .await?
+        .into_iter()
+        .map(vm_mem_snapshot_info)
+        .collect();
+
+    Ok(snapshots)
+}
+
+pub async fn get_vm_mem_snapshot_detail(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    vm_mem_snapshot_id: Uuid,
+) -> VmManageResult<SnapshotInfo> {
+    log::trace!(
+        "Getting detail of vm/mem snapshot {} of {}",
+        vm_mem_snapshot_id,
+        vmid
+    );
+    let element = pool
+        .get_vm_mem_snapshot_db(vmid, vm_mem_snapshot_id)
+        .await?;
+
+    Ok(vm_mem_snapshot_info(element))
+}
+
 pub async fn delete_vm_mem_snapshot(
     pool: &mut VmPool,
     vmid: Uuid,
@@ -778,11 +3482,150 @@ pub async fn delete_vm_mem_snapshot(
     let mem_snapshot_path = pool.mem_snapshot_path(vmid, vm_mem_snapshot_id);
     let vm_snapshot_path = pool.vm_snapshot_path(vmid, vm_mem_snapshot_id);
 
-    std::fs::remove_file(mem_snapshot_path).map_err(|_| VmManageError::IoError)?;
-    std::fs::remove_file(vm_snapshot_path).map_err(|_| VmManageError::IoError)?;
+    std::fs::remove_file(mem_snapshot_path).map_err(VmManageError::IoError)?;
+    std::fs::remove_file(vm_snapshot_path).map_err(VmManageError::IoError)?;
 
     pool.delete_vm_mem_snapshot_db(vmid, vm_mem_snapshot_id)
         .await?;
 
     Ok(())
 }
+
+/// Spawn a fresh firecracker process with `config` and load the vm/mem
+/// snapshot into it instead of booting the kernel. `drive_paths` are
+/// (drive_id, path_on_host) to back the drives of the snapshot with.
+async fn load_vm_mem_snapshot(
+    vmid: Uuid,
+    mut config: Config,
+    snapshot: &PgVmMemSnapshotElement,
+    drive_paths: &[(String, PathBuf)],
+    resume: bool,
+    netns: Option<&Netns>,
+) -> VmManageResult<Machine> {
+    config.vmid.get_or_insert_with(|| vmid.to_string());
+
+    /* Only launch the VMM, the snapshot carries the whole machine state */
+    let pid = spawn_vmm(&config, netns).await?;
+    let core = MachineCore {
+        socket_path: config.socket_path.to_owned().unwrap_or_default(),
+        firecracker_init_timeout: config
+            .agent_init_timeout
+            .unwrap_or(DEFAULT_FIRECRACKER_INIT_TIMEOUT_SECONDS),
+        firecracker_request_timeout: config
+            .agent_request_timeout
+            .unwrap_or(DEFAULT_FIRECRACKER_REQUEST_TIMEOUT_SECONDS),
+        pid,
+        cfg: config,
+    };
+    let mut machine = Machine::rebuild(core).map_err(VmManageError::MachineRebuild)?;
+
+    /* Firecracker takes the drive paths from the snapshot, not from the
+    config, so load paused and point the drives where they belong */
+    let snapshot_load_params = SnapshotLoadParams {
+        enable_diff_snapshots: None,
+        mem_file_path: Some(PathBuf::from(&snapshot.mem_file_path)),
+        mem_backend: None,
+        resume_vm: Some(false),
+        snapshot_path: PathBuf::from(&snapshot.snapshot_path),
+    };
+    if let Err(e) = machine.load_from_snapshot(&snapshot_load_params).await {
+        let _ = kill_vmm(pid).await;
+        return Err(VmManageError::VmMemSnapshotLoad(e));
+    }
+    let res: VmManageResult<()> = async {
+        for (drive_id, path_on_host) in drive_paths {
+            machine
+                .update_guest_drive(drive_id.to_owned(), path_on_host.to_owned())
+                .await
+                .map_err(VmManageError::MachineUpdate)?;
+        }
+        if resume {
+            machine
+                .resume()
+                .await
+                .map_err(VmManageError::MachineResume)?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        let _ = kill_vmm(pid).await;
+        return Err(e);
+    }
+
+    Ok(machine)
+}
+
+pub async fn restore_vm_from_vm_mem_snapshot(
+    pool: &mut VmPool,
+    vmid: Uuid,
+    vm_mem_snapshot_id: Uuid,
+    resume: bool,
+) -> VmManageResult<()> {
+    log::trace!(
+        "Restoring vm {} from vm/mem snapshot {}",
+        vmid,
+        vm_mem_snapshot_id
+    );
+    pool.check_transition(vmid, Transition::Restore).await?;
+    let status = pool.get_status_db(vmid).await?;
+    let snapshot = pool
+        .get_vm_mem_snapshot_db(vmid, vm_mem_snapshot_id)
+        .await?;
+
+    /* Tear down the old firecracker process if any, its socket is reused. The
+    volumes stay attached, but a drive may have been swapped since the
+    snapshot, so point every drive of the volume table at its current path. */
+    let old_core = pool.get_core_db(vmid).await?;
+    let drive_paths: Vec<(String, PathBuf)> = pool
+        .get_volume_db(vmid)
+        .await?
+        .into_iter()
+        .filter_map(|element| {
+            let drive = old_core
+                .cfg
+                .drives
+                .iter()
+                .flatten()
+                .find(|drive| drive.drive_id == element.drive_id)?;
+            Some((element.drive_id, drive.path_on_host.to_owned()))
+        })
+        .collect();
+    if status == RUNNING || status == PAUSED {
+        kill_vmm(old_core.pid).await?;
+    }
+
+    let netns = vm_netns(pool, vmid).await?;
+    let machine = match load_vm_mem_snapshot(
+        vmid,
+        old_core.cfg.to_owned(),
+        &snapshot,
+        &drive_paths,
+        resume,
+        netns.as_ref(),
+    )
+    .await
+    {
+        Ok(machine) => machine,
+        Err(e) => {
+            /* The old process is killed, the vm no longer runs */
+            if status == RUNNING || status == PAUSED {
+                pool.update_core_db(vmid, &old_core, STOPPED).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    let mut core = machine
+        .dump_into_core()
+        .map_err(VmManageError::MachineDumpCore)?;
+    core.cfg.net_ns = netns.as_ref().map(netns_path);
+    let status = match resume {
+        true => Transition::Restore.target_state(),
+        false => PAUSED,
+    };
+    pool.update_core_db(vmid, &core, status).await?;
+
+    log::trace!("Restored vm {}", vmid);
+    Ok(())
+}
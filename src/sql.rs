@@ -10,14 +10,39 @@ pub(crate) const DEFAULT_VM_MEM_SNAPSHOT_TABLE: &'static str = "snapshots";
 pub(crate) const VOLUME_TABLE_NAME: &'static str = "VOLUME_TABLE_NAME";
 pub(crate) const DEFAULT_VOLUME_TABLE: &'static str = "volume";
 
+pub(crate) const NETWORK_TABLE_NAME: &'static str = "NETWORK_TABLE_NAME";
+pub(crate) const DEFAULT_NETWORK_TABLE: &'static str = "network";
+
+pub(crate) const IP_LEASE_TABLE_NAME: &'static str = "IP_LEASE_TABLE_NAME";
+pub(crate) const DEFAULT_IP_LEASE_TABLE: &'static str = "ip_lease";
+
+pub(crate) const SECURITY_GROUP_TABLE_NAME: &'static str = "SECURITY_GROUP_TABLE_NAME";
+pub(crate) const DEFAULT_SECURITY_GROUP_TABLE: &'static str = "security_group";
+
+pub(crate) const SECURITY_GROUP_BINDING_TABLE_NAME: &'static str =
+    "SECURITY_GROUP_BINDING_TABLE_NAME";
+pub(crate) const DEFAULT_SECURITY_GROUP_BINDING_TABLE: &'static str = "security_group_binding";
+
+pub(crate) const PORT_FORWARD_TABLE_NAME: &'static str = "PORT_FORWARD_TABLE_NAME";
+pub(crate) const DEFAULT_PORT_FORWARD_TABLE: &'static str = "port_forward";
+
+pub(crate) const DISK_SNAPSHOT_TABLE_NAME: &'static str = "DISK_SNAPSHOT_TABLE_NAME";
+pub(crate) const DEFAULT_DISK_SNAPSHOT_TABLE: &'static str = "disk_snapshot";
+
+pub(crate) const NETNS_TABLE_NAME: &'static str = "NETNS_TABLE_NAME";
+pub(crate) const DEFAULT_NETNS_TABLE: &'static str = "netns";
+
 pub(crate) const CREATE_VMVIEWCONFIGS_TABLE_SQL: &'static str = r#"
     CREATE TABLE if not exists $1 (
         vmid                UUID PRIMARY KEY,
-        config              JSON
+        config              JSON,
+        created_at          TIMESTAMPTZ DEFAULT now()
     );
 "#;
-pub(crate) const DROP_VMVIEWCONFIGS_TABLE_SQL: &'static str = r#"
-    DROP TABLE if exists $1;
+/* Older releases did not record when a vm was created, existing rows get
+ * the time of the migration. */
+pub(crate) const MIGRATE_VMVIEWCONFIGS_TABLE_SQL: &'static str = r#"
+    ALTER TABLE $1 ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ DEFAULT now();
 "#;
 pub(crate) const INSERT_VMVIEWCONFIGS_BY_VMID: &'static str = r#"
     INSERT INTO $1 (vmid, config) VALUES ($2, $3);
@@ -33,12 +58,12 @@ pub(crate) const CREATE_MACHINE_CORE_TABLE_SQL: &'static str = r#"
         status              INT,
     );
 "#;
-pub(crate) const DROP_MAHCINE_CORE_TABLE_SQL: &'static str = r#"
-    DROP TABLE if exists $1;
-"#;
 pub(crate) const GET_MACHINE_CORE_BY_VMID: &'static str = r#"
     SELECT * FROM $1 WHERE vmid = $2;
 "#;
+pub(crate) const GET_MACHINE_CORE_ALL: &'static str = r#"
+    SELECT * FROM $1;
+"#;
 pub(crate) const INSERT_MACHINE_CORE_BY_VMID: &'static str = r#"
     INSERT INTO $1 (vmid, core, status) VALUES ($2, $3, $4);
 "#;
@@ -48,38 +73,76 @@ pub(crate) const DELETE_MACHINE_CORE_BY_VMID: &'static str = r#"
 pub(crate) const UPDATE_MACHINE_CORE_BY_VMID: &'static str = r#"
     UPDATE $1 SET core = $2, status = $3 WHERE vmid = $4
 "#;
+pub(crate) const LIST_MACHINE_WITH_CONFIG: &'static str = r#"
+    SELECT core.vmid, core.status, config.config, config.created_at
+    FROM $1 core JOIN $2 config ON core.vmid = config.vmid
+    WHERE ($3::INT IS NULL OR core.status = $3)
+        AND ($4::TEXT IS NULL OR config.config->>'kernel_name' = $4)
+        AND ($5::UUID IS NULL OR core.vmid > $5)
+    ORDER BY core.vmid
+    LIMIT $6;
+"#;
 
 pub(crate) const CREATE_VM_MEM_SNAPSHOT_TABLE_SQL: &'static str = r#"
     CREATE TABLE if not exists $1 (
         vmid                UUID,
         snapshot_id         UUID,
         mem_file_path       VARCHAR(256),
-        snapshot_path       VARCHAR(256)
+        snapshot_path       VARCHAR(256),
+        status              INT,
+        created_at          TIMESTAMPTZ DEFAULT now()
     );
 "#;
-pub(crate) const DROP_VM_MEM_SNAPSHOT_TABLE_SQL: &'static str = r#"
-    DROP TABLE if exists $1;
+/* Older releases recorded neither the state a snapshot was taken in nor its
+ * time. Firecracker only snapshots paused vms, $2 is PAUSED. */
+pub(crate) const MIGRATE_VM_MEM_SNAPSHOT_TABLE_SQL: &'static str = r#"
+    ALTER TABLE $1
+        ADD COLUMN IF NOT EXISTS status INT,
+        ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ DEFAULT now();
+"#;
+pub(crate) const BACKFILL_VM_MEM_SNAPSHOT_STATUS_SQL: &'static str = r#"
+    UPDATE $1 SET status = $2 WHERE status IS NULL;
 "#;
 pub(crate) const INSERT_VM_MEM_SNAPSHOT_BY_ID: &'static str = r#"
-    INSERT INTO $1 (vmid, snapshot_id, mem_file_path, snapshot_path)
-    VALUES ($2, $3, $4, $5);
+    INSERT INTO $1 (vmid, snapshot_id, mem_file_path, snapshot_path, status)
+    VALUES ($2, $3, $4, $5, $6);
 "#;
 pub(crate) const DELETE_VM_MEM_SNAPSHOT_BY_ID: &'static str = r#"
     DELETE * FROM $1 WHERE vmid = $2 AND snapshot_id = $3;
 "#;
+pub(crate) const GET_VM_MEM_SNAPSHOT_BY_ID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2 AND snapshot_id = $3;
+"#;
+pub(crate) const GET_VM_MEM_SNAPSHOT_ALL: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2 ORDER BY created_at;
+"#;
 
 pub(crate) const CREATE_VOLUME_TABLE_SQL: &'static str = r#"
     CREATE TABLE if not exists $1 (
         vmid                UUID,
-        volume_id           UUID
+        volume_id           UUID UNIQUE,
+        drive_id            TEXT,
+        image_id            UUID
     );
 "#;
-pub(crate) const DROP_VOLUME_TABLE_SQL: &'static str = r#"
-    DROP TABLE if exists $1;
+/* Volume tables created before data drives and image-backed volumes existed
+ * lack drive_id and image_id; every volume they hold is a root volume. */
+pub(crate) const MIGRATE_VOLUME_TABLE_SQL: &'static str = r#"
+    ALTER TABLE $1
+        ADD COLUMN IF NOT EXISTS drive_id TEXT,
+        ADD COLUMN IF NOT EXISTS image_id UUID;
+"#;
+pub(crate) const BACKFILL_VOLUME_DRIVE_ID_SQL: &'static str = r#"
+    UPDATE $1 SET drive_id = 'rootfs' WHERE drive_id IS NULL;
+"#;
+/* $2 is the name postgres gives the UNIQUE constraint of a fresh table,
+ * so this is a no-op there. */
+pub(crate) const MIGRATE_VOLUME_ID_UNIQUE_SQL: &'static str = r#"
+    CREATE UNIQUE INDEX IF NOT EXISTS $2 ON $1 (volume_id);
 "#;
 pub(crate) const INSERT_VOLUME_BY_ID: &'static str = r#"
-    INSERT INTO $1 (vmid, volume_id)
-    VALUES ($2, $3);
+    INSERT INTO $1 (vmid, volume_id, drive_id, image_id)
+    VALUES ($2, $3, $4, $5);
 "#;
 pub(crate) const DELETE_VOLUME_BY_ID: &'static str = r#"
     DELETE * FROM $1 WHERE vmid = $2 AND volume_id = $3;
@@ -87,3 +150,163 @@ pub(crate) const DELETE_VOLUME_BY_ID: &'static str = r#"
 pub(crate) const GET_VOLUME_ALL: &'static str = r#"
     SELECT * FROM $1 WHERE vmid = $2;
 "#;
+pub(crate) const GET_VOLUME_BY_VOLUME_ID: &'static str = r#"
+    SELECT * FROM $1 WHERE volume_id = $2;
+"#;
+
+pub(crate) const CREATE_NETWORK_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID,
+        tap_id              INT UNIQUE,
+        guest_mac           TEXT UNIQUE
+    );
+"#;
+pub(crate) const INSERT_NETWORK_BY_ID: &'static str = r#"
+    INSERT INTO $1 (vmid, tap_id, guest_mac)
+    VALUES ($2, $3, $4);
+"#;
+pub(crate) const DELETE_NETWORK_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_NETWORK_BY_VMID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_NETWORK_ALL: &'static str = r#"
+    SELECT * FROM $1;
+"#;
+
+pub(crate) const CREATE_IP_LEASE_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID,
+        tap_id              INT UNIQUE,
+        subnet              TEXT UNIQUE,
+        prefix_len          INT
+    );
+"#;
+pub(crate) const INSERT_IP_LEASE_BY_ID: &'static str = r#"
+    INSERT INTO $1 (vmid, tap_id, subnet, prefix_len)
+    VALUES ($2, $3, $4, $5);
+"#;
+pub(crate) const DELETE_IP_LEASE_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_IP_LEASE_BY_VMID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2 ORDER BY tap_id;
+"#;
+pub(crate) const GET_IP_LEASE_ALL: &'static str = r#"
+    SELECT * FROM $1;
+"#;
+
+pub(crate) const CREATE_SECURITY_GROUP_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        id                  UUID PRIMARY KEY,
+        name                TEXT,
+        rules               JSON,
+        created_at          TIMESTAMPTZ DEFAULT now()
+    );
+"#;
+pub(crate) const INSERT_SECURITY_GROUP: &'static str = r#"
+    INSERT INTO $1 (id, name, rules)
+    VALUES ($2, $3, $4);
+"#;
+pub(crate) const DELETE_SECURITY_GROUP_BY_ID: &'static str = r#"
+    DELETE * FROM $1 WHERE id = $2;
+"#;
+pub(crate) const GET_SECURITY_GROUP_BY_ID: &'static str = r#"
+    SELECT * FROM $1 WHERE id = $2;
+"#;
+pub(crate) const GET_SECURITY_GROUP_ALL: &'static str = r#"
+    SELECT * FROM $1 ORDER BY created_at;
+"#;
+
+pub(crate) const CREATE_SECURITY_GROUP_BINDING_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID,
+        group_id            UUID,
+        UNIQUE (vmid, group_id)
+    );
+"#;
+pub(crate) const INSERT_SECURITY_GROUP_BINDING: &'static str = r#"
+    INSERT INTO $1 (vmid, group_id)
+    VALUES ($2, $3)
+    ON CONFLICT DO NOTHING;
+"#;
+pub(crate) const DELETE_SECURITY_GROUP_BINDING: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2 AND group_id = $3;
+"#;
+pub(crate) const DELETE_SECURITY_GROUP_BINDING_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_SECURITY_GROUP_BINDING_BY_VMID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_SECURITY_GROUP_BINDING_BY_GROUP: &'static str = r#"
+    SELECT * FROM $1 WHERE group_id = $2;
+"#;
+
+pub(crate) const CREATE_PORT_FORWARD_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID,
+        protocol            TEXT,
+        host_port           INT,
+        guest_ip            TEXT,
+        guest_port          INT,
+        UNIQUE (protocol, host_port)
+    );
+"#;
+pub(crate) const INSERT_PORT_FORWARD: &'static str = r#"
+    INSERT INTO $1 (vmid, protocol, host_port, guest_ip, guest_port)
+    VALUES ($2, $3, $4, $5, $6);
+"#;
+pub(crate) const DELETE_PORT_FORWARD_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_PORT_FORWARD_BY_VMID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_PORT_FORWARD_BY_PROTOCOL: &'static str = r#"
+    SELECT * FROM $1 WHERE protocol = $2;
+"#;
+
+pub(crate) const CREATE_DISK_SNAPSHOT_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID,
+        snapshot_id         UUID,
+        volume_id           UUID,
+        volume_snapshot_id  UUID,
+        created_at          TIMESTAMPTZ DEFAULT now()
+    );
+"#;
+pub(crate) const INSERT_DISK_SNAPSHOT: &'static str = r#"
+    INSERT INTO $1 (vmid, snapshot_id, volume_id, volume_snapshot_id)
+    VALUES ($2, $3, $4, $5);
+"#;
+pub(crate) const DELETE_DISK_SNAPSHOT_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_DISK_SNAPSHOT_BY_ID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2 AND snapshot_id = $3;
+"#;
+pub(crate) const GET_DISK_SNAPSHOT_ALL: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2 ORDER BY created_at;
+"#;
+
+pub(crate) const CREATE_NETNS_TABLE_SQL: &'static str = r#"
+    CREATE TABLE if not exists $1 (
+        vmid                UUID PRIMARY KEY,
+        transit_index       INT UNIQUE
+    );
+"#;
+pub(crate) const INSERT_NETNS: &'static str = r#"
+    INSERT INTO $1 (vmid, transit_index)
+    VALUES ($2, $3);
+"#;
+pub(crate) const DELETE_NETNS_BY_VMID: &'static str = r#"
+    DELETE * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_NETNS_BY_VMID: &'static str = r#"
+    SELECT * FROM $1 WHERE vmid = $2;
+"#;
+pub(crate) const GET_NETNS_ALL: &'static str = r#"
+    SELECT * FROM $1;
+"#;
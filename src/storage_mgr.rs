@@ -13,9 +13,19 @@ use uuid::Uuid;
 
 use crate::{error::VmManageResult, pool::VmPool, storage_models::*};
 
-pub async fn create_volume(pool: &mut VmPool, size: i32, parent: Option<Uuid>) -> VmManageResult<Uuid> {
+/// Create a volume, blank unless a `parent` volume or an `image` to copy is given
+pub async fn create_volume(
+    pool: &mut VmPool,
+    size: i32,
+    parent: Option<Uuid>,
+    image: Option<Uuid>,
+) -> VmManageResult<Uuid> {
     let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/volume");
-    let req = VolumeCreateRequest { size, parent };
+    let req = VolumeCreateRequest {
+        size,
+        parent,
+        image,
+    };
     let res = pool
         .storage_client
         .post(url)
@@ -28,6 +38,23 @@ pub async fn create_volume(pool: &mut VmPool, size: i32, parent: Option<Uuid>) -
     Ok(res.volume)
 }
 
+/// Size in MiB of a volume
+pub async fn get_volume_size(pool: &mut VmPool, volume: Uuid) -> VmManageResult<i32> {
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/volume");
+    let req = VolumeDetailRequest { volume };
+    let res = pool
+        .storage_client
+        .get(url)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<VolumeDetailResponse>()
+        .await?;
+
+    Ok(res.size)
+}
+
 pub async fn delete_volume(pool: &mut VmPool, volume: Uuid) -> VmManageResult<Uuid> {
     let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/volume");
     let req = VolumeDeleteRequest { volume };
@@ -100,3 +127,71 @@ pub async fn delete_volume_snapshot(
 
     Ok(())
 }
+
+pub async fn list_volume_snapshot(pool: &mut VmPool, volume: Uuid) -> VmManageResult<Vec<Uuid>> {
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/snapshot");
+    let req = SnapshotListRequest { volume };
+    let res = pool
+        .storage_client
+        .get(url)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SnapshotListResponse>()
+        .await?;
+
+    Ok(res.snapshots)
+}
+
+/// Revert a volume to a snapshot, the volume must not be attached
+pub async fn rollback_volume_snapshot(
+    pool: &mut VmPool,
+    volume: Uuid,
+    snapshot: Uuid,
+) -> VmManageResult<()> {
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/snapshot/rollback");
+    let req = SnapshotRollbackRequest { volume, snapshot };
+    pool.storage_client
+        .post(url)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Publish a snapshot as an image new volumes could be cloned from
+pub async fn publish_image(pool: &mut VmPool, volume: Uuid, snapshot: Uuid) -> VmManageResult<Uuid> {
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/image");
+    let req = ImagePublishRequest { volume, snapshot };
+    let res = pool
+        .storage_client
+        .post(url)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ImagePublishResponse>()
+        .await?;
+
+    Ok(res.image)
+}
+
+/// Size in MiB of a published image
+pub async fn get_image_size(pool: &mut VmPool, image: Uuid) -> VmManageResult<i32> {
+    let url = format!("{}{}", pool.storage_mgr_addr, "/api/v1/image");
+    let req = ImageDetailRequest { image };
+    let res = pool
+        .storage_client
+        .get(url)
+        .json(&req)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ImageDetailResponse>()
+        .await?;
+
+    Ok(res.size)
+}
@@ -11,7 +11,10 @@ pub struct ErrorResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VolumeCreateRequest {
     pub size: i32, // in MB
+    /// Volume the new one is a copy-on-write child of
     pub parent: Option<Uuid>,
+    /// Published image the new volume is a copy-on-write child of
+    pub image: Option<Uuid>,
 }
 
 
@@ -56,6 +59,12 @@ pub struct VolumeDetailRequest {
     pub volume: Uuid,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VolumeDetailResponse {
+    pub volume: Uuid,
+    pub size: i32, // in MB
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotCreateRequest {
     pub volume: Uuid,
@@ -95,6 +104,17 @@ pub struct ImagePublishRequest {
     pub snapshot: Uuid
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageDetailRequest {
+    pub image: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageDetailResponse {
+    pub image: Uuid,
+    pub size: i32, // in MB
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImagePublishResponse {
     pub volume: Uuid,